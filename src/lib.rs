@@ -0,0 +1,3968 @@
+//! Partition-based lossy compression of PNG images
+//!
+//! An [`Image`] is read with [`read_image`] or [`decode_image`], partitioned
+//! into blocks of a single color by a [`Compressor`], then encoded with
+//! [`encode_png`] and [`optimize_png`]. [`compress_image`] partitions an image
+//! in a single call, with the default settings.
+//!
+//! Reading, encoding and optimizing report their failures as a
+//! [`PngpartError`], the library never prints anything or exits.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{BufWriter, Read};
+use std::num::NonZeroU8;
+use std::ops::{ControlFlow, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod rng;
+mod table;
+
+pub use rng::SplitMix64;
+use table::{Sums, SummedAreaTable};
+
+/// The size of every channel of an [`Image`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BitDepth {
+    Eight,
+    Sixteen
+}
+
+impl BitDepth {
+    /// The number of bytes of a single channel
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::Eight => 1,
+            Self::Sixteen => 2
+        }
+    }
+
+    /// The largest value of a channel
+    pub fn max_value(self) -> u64 {
+        match self {
+            Self::Eight => 0xff,
+            Self::Sixteen => 0xffff
+        }
+    }
+
+    // How much larger a squared deviation is than the same deviation between
+    // 8-bit channels, so the tolerance has the same meaning at every depth
+    fn variance_scale(self) -> u64 {
+        match self {
+            Self::Eight => 1,
+            Self::Sixteen => 257 * 257
+        }
+    }
+}
+
+/// An 8-bit or 16-bit grayscale or RGB image, with or without alpha
+#[derive(Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+
+    /// The number of channels per pixel, 1 for grayscale, 2 for grayscale
+    /// with alpha, 3 for RGB or 4 for RGBA
+    pub channels: usize,
+
+    pub depth: BitDepth,
+
+    /// Pixels in row-major order, one sample per channel (the gray level or
+    /// red, green and blue, then alpha). 16-bit samples are big-endian like in
+    /// PNG, so its length is always `width * height * channels * depth.bytes()`
+    pub data: Box<[u8]>
+}
+
+/// Which channels the pixels of an [`Image`] have
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorType {
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba
+}
+
+impl ColorType {
+    /// The color type of images with `channels` channels per pixel
+    pub fn from_channels(channels: usize) -> Self {
+        match channels {
+            1 => Self::Gray,
+            2 => Self::GrayAlpha,
+            3 => Self::Rgb,
+            _ => Self::Rgba
+        }
+    }
+
+    pub fn channels(self) -> usize {
+        match self {
+            Self::Gray => 1,
+            Self::GrayAlpha => 2,
+            Self::Rgb => 3,
+            Self::Rgba => 4
+        }
+    }
+
+    pub fn is_gray(self) -> bool {
+        matches!(self, Self::Gray | Self::GrayAlpha)
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(self, Self::GrayAlpha | Self::Rgba)
+    }
+}
+
+// Reads a big-endian sample of either depth
+fn read_sample(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+// Writes a big-endian sample of either depth, given by the length of `out`.
+// The trailing bytes of a big-endian u16 are the sample at both depths
+fn write_sample(value: u64, out: &mut [u8]) {
+    out.copy_from_slice(&(value as u16).to_be_bytes()[2 - out.len()..]);
+}
+
+impl Image {
+    pub fn color_type(&self) -> ColorType {
+        ColorType::from_channels(self.channels)
+    }
+
+    fn pixels(&self) -> std::slice::Chunks<'_, u8> {
+        self.data.chunks(self.channels * self.depth.bytes())
+    }
+
+    /// Whether the image has no alpha channel or is fully opaque
+    pub fn is_opaque(&self) -> bool {
+        if !self.color_type().has_alpha() {
+            return true;
+        }
+
+        let size = self.depth.bytes();
+        self.pixels().all(|pixel| pixel[pixel.len() - size..].iter().all(|&byte| byte == 0xff))
+    }
+
+    /// Whether every pixel of the image is a shade of gray
+    pub fn is_gray(&self) -> bool {
+        if self.color_type().is_gray() {
+            return true;
+        }
+
+        let size = self.depth.bytes();
+        self.pixels().all(|pixel| {
+            let (r, rest) = pixel.split_at(size);
+            let (g, b) = rest.split_at(size);
+            r == g && r == &b[..size]
+        })
+    }
+
+    /// The color type with the fewest channels that holds the image without
+    /// losing information
+    pub fn smallest_color_type(&self) -> ColorType {
+        match (self.is_gray(), self.is_opaque()) {
+            (true, true) => ColorType::Gray,
+            (true, false) => ColorType::GrayAlpha,
+            (false, true) => ColorType::Rgb,
+            (false, false) => ColorType::Rgba
+        }
+    }
+
+    /// Whether converting the image to `color_type` keeps every pixel intact
+    pub fn converts_losslessly(&self, color_type: ColorType) -> bool {
+        (color_type.has_alpha() || self.is_opaque()) && (!color_type.is_gray() || self.is_gray())
+    }
+
+    /// Converts the image to another color type
+    ///
+    /// Colors are turned into their luma, and the alpha channel is dropped
+    /// when converting to a color type without one. Use
+    /// [`Image::converts_losslessly`] to know whether this loses information.
+    pub fn to_color_type(&self, color_type: ColorType) -> Image {
+        let from = self.color_type();
+        let size = self.depth.bytes();
+        let color_len = if from.is_gray() { size } else { 3 * size };
+
+        let mut data = Vec::with_capacity(self.width * self.height * color_type.channels() * size);
+        for pixel in self.pixels() {
+            let (color, alpha) = pixel.split_at(color_len);
+            match (from.is_gray(), color_type.is_gray()) {
+                (true, false) => for _ in 0..3 {
+                    data.extend_from_slice(color);
+                },
+                (false, true) => {
+                    // Rec. 601 luma, the weights add up to 1000 so grays are
+                    // kept exactly
+                    let luma = color.chunks(size).zip([299, 587, 114])
+                        .map(|(sample, weight)| read_sample(sample) * weight)
+                        .sum::<u64>();
+                    let start = data.len();
+                    data.resize(start + size, 0);
+                    write_sample((luma + 500) / 1000, &mut data[start..]);
+                },
+                _ => data.extend_from_slice(color)
+            }
+
+            if color_type.has_alpha() {
+                if from.has_alpha() {
+                    data.extend_from_slice(alpha);
+                } else {
+                    data.extend(std::iter::repeat_n(0xff, size));
+                }
+            }
+        }
+
+        Image {
+            width: self.width,
+            height: self.height,
+            channels: color_type.channels(),
+            depth: self.depth,
+            data: data.into()
+        }
+    }
+
+    /// Composites the image over an opaque `background` color, leaving it
+    /// without an alpha channel
+    ///
+    /// Gray images stay gray if the background is a gray too. Images without
+    /// an alpha channel are returned as they are.
+    pub fn flatten(&self, background: [u8; 3]) -> Image {
+        let from = self.color_type();
+        if !from.has_alpha() {
+            return self.clone();
+        }
+
+        let to = match from.is_gray() && background.iter().all(|&c| c == background[0]) {
+            true => ColorType::Gray,
+            false => ColorType::Rgb
+        };
+
+        let size = self.depth.bytes();
+        let max = self.depth.max_value();
+        let background = background.map(|c| c as u64 * max / 255);
+
+        let mut data = vec![0; self.width * self.height * to.channels() * size];
+        for (pixel, out) in self.pixels().zip(data.chunks_mut(to.channels() * size)) {
+            let mut samples = [0; 4];
+            for (sample, value) in pixel.chunks(size).zip(&mut samples) {
+                *value = read_sample(sample);
+            }
+
+            let (color, alpha) = match from {
+                ColorType::GrayAlpha => ([samples[0]; 3], samples[1]),
+                _ => ([samples[0], samples[1], samples[2]], samples[3])
+            };
+
+            for ((sample, c), b) in out.chunks_mut(size).zip(color).zip(background) {
+                write_sample((c * alpha + b * (max - alpha) + max / 2) / max, sample);
+            }
+        }
+
+        Image { width: self.width, height: self.height, channels: to.channels(), depth: self.depth, data: data.into() }
+    }
+
+    /// Copies the `width` by `height` rectangle whose top left corner is at
+    /// (`x`, `y`)
+    ///
+    /// # Panics
+    ///
+    /// If the rectangle doesn't fit in the image.
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Image {
+        assert!(
+            x.checked_add(width).is_some_and(|x_max| x_max <= self.width) &&
+                y.checked_add(height).is_some_and(|y_max| y_max <= self.height),
+            "the rectangle doesn't fit in the image"
+        );
+
+        let pixel_len = self.channels * self.depth.bytes();
+        let mut data = Vec::with_capacity(width * height * pixel_len);
+        for row in self.data.chunks(self.width.max(1) * pixel_len).skip(y).take(height) {
+            data.extend_from_slice(&row[x * pixel_len..(x + width) * pixel_len]);
+        }
+
+        Image { width, height, channels: self.channels, depth: self.depth, data: data.into() }
+    }
+
+    /// Scales the image to `width` by `height` pixels with a triangle filter
+    ///
+    /// When shrinking, every pixel averages the whole area of the image it
+    /// covers. The colors are weighted by their alpha, so that transparent
+    /// pixels don't bleed their color into the opaque ones.
+    pub fn resize(&self, width: usize, height: usize) -> Image {
+        let size = self.depth.bytes();
+        let mut data = vec![0; width * height * self.channels * size];
+        if self.width == 0 || self.height == 0 {
+            return Image { width, height, channels: self.channels, depth: self.depth, data: data.into() };
+        }
+
+        let max = self.depth.max_value() as f64;
+        let color_len = self.channels - self.color_type().has_alpha() as usize;
+        let premultiply = |pixel: &mut [f64]| if color_len < pixel.len() {
+            let alpha = pixel[color_len] / max;
+            pixel[..color_len].iter_mut().for_each(|c| *c *= alpha);
+        };
+
+        let mut samples: Vec<f64> = self.data.chunks(size).map(|sample| read_sample(sample) as f64).collect();
+        samples.chunks_mut(self.channels).for_each(premultiply);
+
+        // The rows are scaled first, then the columns of the result
+        let rows = resample(&samples, self.channels, self.width, width, self.height, true);
+        let resized = resample(&rows, self.channels, self.height, height, width, false);
+
+        for (pixel, out) in resized.chunks(self.channels).zip(data.chunks_mut(self.channels * size)) {
+            let alpha = if color_len < self.channels { pixel[color_len] / max } else { 1.0 };
+            for (k, (&value, sample)) in pixel.iter().zip(out.chunks_mut(size)).enumerate() {
+                let value = match k < color_len {
+                    true if alpha > 0.0 => value / alpha,
+                    true => 0.0,
+                    false => value
+                };
+
+                write_sample(value.round().clamp(0.0, max) as u64, sample);
+            }
+        }
+
+        Image { width, height, channels: self.channels, depth: self.depth, data: data.into() }
+    }
+
+    /// Softens the edges between areas of a single color, such as the blocks
+    /// of a reconstructed image, by scattering the colors on both sides of
+    /// every edge over `radius` pixels
+    ///
+    /// Every pixel within `radius` pixels of an edge randomly takes the color
+    /// across it, with a chance that goes from nearly one half next to the
+    /// edge down to none past the radius. No new colors are made, so the image stays
+    /// compressible, but gradients look smoother than in flat blocks. The
+    /// randomness is drawn from `rng`, such as the one of [`Compressor::rng`],
+    /// so the same seed always scatters the pixels the same way.
+    pub fn dither(&self, radius: usize, rng: &mut SplitMix64) -> Image {
+        let pixel_len = self.channels * self.depth.bytes();
+        let at = |x: usize, y: usize| &self.data[(y * self.width + x) * pixel_len..][..pixel_len];
+        let mut data = self.data.to_vec();
+
+        // The distance to the nearest pixel of another color along an axis and
+        // its position, searching up to `radius` pixels away on both sides of
+        // `pos`, which is in `0..len`
+        let nearest = |pos: usize, len: usize, differs: &dyn Fn(usize) -> bool| {
+            (1..=radius).find_map(|d| {
+                let before = pos.checked_sub(d).filter(|&p| differs(p));
+                let after = Some(pos + d).filter(|&p| p < len && differs(p));
+                before.or(after).map(|p| (d, p))
+            })
+        };
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = at(x, y);
+                let across = [
+                    nearest(x, self.width, &|p| at(p, y) != color).map(|(d, p)| (d, at(p, y))),
+                    nearest(y, self.height, &|p| at(x, p) != color).map(|(d, p)| (d, at(x, p)))
+                ];
+
+                for (d, other) in across.into_iter().flatten() {
+                    let chance = (radius + 1 - d) as f64 / (2 * (radius + 1)) as f64;
+                    if rng.next_f64() < chance {
+                        let idx = (y * self.width + x) * pixel_len;
+                        data[idx..idx + pixel_len].copy_from_slice(other);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Image { width: self.width, height: self.height, channels: self.channels, depth: self.depth, data: data.into() }
+    }
+
+    /// Draws the edges of every rectangle of `bounds` in an opaque `color`,
+    /// such as the blocks of a partition, see [`Compressor::blocks`]
+    ///
+    /// Only the top and left edges of a rectangle are drawn, and the right and
+    /// bottom ones that lie on the edge of the image, so rectangles that tile
+    /// the image are separated by lines 1 pixel wide. Gray images become RGB
+    /// unless `color` is a gray too.
+    pub fn outline(&self, bounds: &[Bound], color: [u8; 3]) -> Image {
+        let img = match self.color_type() {
+            ColorType::Gray | ColorType::GrayAlpha if color.iter().all(|&c| c == color[0]) => self.clone(),
+            ColorType::Gray => self.to_color_type(ColorType::Rgb),
+            ColorType::GrayAlpha => self.to_color_type(ColorType::Rgba),
+            _ => self.clone()
+        };
+
+        let size = img.depth.bytes();
+        let pixel_len = img.channels * size;
+        let max = img.depth.max_value();
+
+        // The alpha, if any, comes right after the color
+        let [r, g, b] = color.map(|c| c as u64 * max / 255);
+        let samples = if img.color_type().is_gray() { [r, max, 0, 0] } else { [r, g, b, max] };
+        let mut pixel = vec![0; pixel_len];
+        for (sample, &value) in pixel.chunks_mut(size).zip(&samples) {
+            write_sample(value, sample);
+        }
+
+        let mut data = img.data.to_vec();
+        let mut paint = |x: usize, y: usize| {
+            let idx = (y * img.width + x) * pixel_len;
+            data[idx..idx + pixel_len].copy_from_slice(&pixel);
+        };
+
+        for bound in bounds.iter().filter(|bound| bound.area() > 0) {
+            for x in bound.x_min..bound.x_max {
+                paint(x, bound.y_min);
+                if bound.y_max == img.height {
+                    paint(x, bound.y_max - 1);
+                }
+            }
+
+            for y in bound.y_min..bound.y_max {
+                paint(bound.x_min, y);
+                if bound.x_max == img.width {
+                    paint(bound.x_max - 1, y);
+                }
+            }
+        }
+
+        Image { data: data.into(), ..img }
+    }
+
+    // Every pixel as an 8-bit RGBA color, 16-bit samples are rounded down
+    fn rgba8(&self) -> Vec<[u8; 4]> {
+        let size = self.depth.bytes();
+        let max = self.depth.max_value();
+        self.to_color_type(ColorType::Rgba).data.chunks(4 * size).map(|pixel| {
+            std::array::from_fn(|k| (read_sample(&pixel[k * size..(k + 1) * size]) * 255 / max) as u8)
+        }).collect()
+    }
+
+    /// The image as indices into a palette of its distinct colors, or `None`
+    /// if it has more than 256 of them
+    ///
+    /// The colors of the palette are 8-bit, 16-bit images are rounded down.
+    pub fn to_indexed(&self) -> Option<IndexedImage> {
+        let mut palette = Vec::new();
+        let mut index_of = HashMap::new();
+        let mut indices = Vec::with_capacity(self.width * self.height);
+        for color in self.rgba8() {
+            let index = *index_of.entry(color).or_insert_with(|| {
+                palette.push(color);
+                palette.len() - 1
+            });
+            indices.push(u8::try_from(index).ok()?);
+        }
+
+        Some(IndexedImage { width: self.width, height: self.height, palette, indices: indices.into() })
+    }
+
+    /// Maps every pixel to the closest color of `palette`, by their squared
+    /// distance over every channel
+    ///
+    /// # Panics
+    ///
+    /// If the palette is empty or has more than 256 colors.
+    pub fn to_palette(&self, palette: &[[u8; 4]]) -> IndexedImage {
+        assert!(!palette.is_empty() && palette.len() <= 256, "the palette must have between 1 and 256 colors");
+
+        // Blocks are a single color, so most pixels are a color seen before
+        let mut index_of = HashMap::new();
+        let indices = self.rgba8().into_iter().map(|color| *index_of.entry(color).or_insert_with(|| {
+            let distance = |entry: &[u8; 4]| -> u32 {
+                entry.iter().zip(&color).map(|(&a, &b)| (a.abs_diff(b) as u32).pow(2)).sum()
+            };
+            (0..palette.len()).min_by_key(|&i| distance(&palette[i])).unwrap() as u8
+        })).collect();
+
+        IndexedImage { width: self.width, height: self.height, palette: palette.to_vec(), indices }
+    }
+
+    /// Reduces the image to a palette of at most `colors` colors, between 1
+    /// and 256
+    ///
+    /// Images with few enough colors keep them exactly like with
+    /// [`Image::to_indexed`]. Otherwise, colors that are close on every
+    /// channel are merged into their average, weighted by their number of
+    /// pixels, and how close they have to be grows until few enough colors
+    /// are left. A compressed image has a single color per block, many of them
+    /// only slightly apart, so this mostly merges colors nobody can tell apart.
+    pub fn quantize(&self, colors: usize) -> IndexedImage {
+        let colors = colors.clamp(1, 256);
+        if let Some(indexed) = self.to_indexed().filter(|indexed| indexed.palette.len() <= colors) {
+            return indexed;
+        }
+
+        let pixels = self.rgba8();
+        let mut counts: HashMap<[u8; 4], u64> = HashMap::new();
+        for &color in &pixels {
+            *counts.entry(color).or_default() += 1;
+        }
+
+        // The colors in the same cell of a grid of `step` wide cells are
+        // merged. Counting stops as soon as there are too many cells, so the
+        // narrow grids are rejected quickly
+        let cell = |color: [u8; 4], step: u16| color.map(|c| (c as u16 / step) as u8);
+        let fits = |step| {
+            let mut cells = HashSet::new();
+            counts.keys().all(|&color| {
+                cells.insert(cell(color, step));
+                cells.len() <= colors
+            })
+        };
+        let step = (2..=256).find(|&step| fits(step)).unwrap_or(256);
+
+        // The cells are numbered in the order their colors appear, so that the
+        // palette doesn't depend on the order of the hash map
+        let mut index_of = HashMap::new();
+        let mut sums: Vec<[u64; 5]> = Vec::new();
+        let indices = pixels.iter().map(|&color| *index_of.entry(cell(color, step)).or_insert_with(|| {
+            sums.push([0; 5]);
+            (sums.len() - 1) as u8
+        })).collect();
+
+        for (&color, &count) in &counts {
+            let sum = &mut sums[index_of[&cell(color, step)] as usize];
+            for (s, c) in sum.iter_mut().zip(color) {
+                *s += c as u64 * count;
+            }
+            sum[4] += count;
+        }
+
+        let palette = sums.iter().map(|sum| std::array::from_fn(|k| ((sum[k] + sum[4] / 2) / sum[4]) as u8)).collect();
+        IndexedImage { width: self.width, height: self.height, palette, indices }
+    }
+
+    /// Converts an image of the image crate, keeping its channels
+    ///
+    /// 8-bit and 16-bit images keep their depth, floating point images are
+    /// converted to 16-bit RGBA. Nothing is scaled down to 8 bits, since images
+    /// hold 16-bit samples like [`decode_image`] keeps them, and the blocks are
+    /// measured and painted at that precision. [`Image::into_rgba_image`]
+    /// rounds them once the image is compressed.
+    #[cfg(feature = "image")]
+    pub fn from_dynamic(img: &image::DynamicImage) -> Image {
+        use image::DynamicImage;
+
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let eight = |channels, data: &[u8]| Image {
+            width,
+            height,
+            channels,
+            depth: BitDepth::Eight,
+            data: data.into()
+        };
+        let sixteen = |channels, data: &[u16]| Image {
+            width,
+            height,
+            channels,
+            depth: BitDepth::Sixteen,
+            data: data.iter().flat_map(|sample| sample.to_be_bytes()).collect()
+        };
+
+        match img {
+            DynamicImage::ImageLuma8(img) => eight(1, img),
+            DynamicImage::ImageLumaA8(img) => eight(2, img),
+            DynamicImage::ImageRgb8(img) => eight(3, img),
+            DynamicImage::ImageRgba8(img) => eight(4, img),
+            DynamicImage::ImageLuma16(img) => sixteen(1, img),
+            DynamicImage::ImageLumaA16(img) => sixteen(2, img),
+            DynamicImage::ImageRgb16(img) => sixteen(3, img),
+            DynamicImage::ImageRgba16(img) => sixteen(4, img),
+            img => sixteen(4, &img.to_rgba16())
+        }
+    }
+
+    /// Copies an 8-bit RGBA image of the image crate, whose pixels are laid
+    /// out the same way, without converting them. Converting it with
+    /// [`From`] moves the pixels instead
+    #[cfg(feature = "image")]
+    pub fn from_rgba_image(img: &image::RgbaImage) -> Image {
+        Image {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            channels: 4,
+            depth: BitDepth::Eight,
+            data: img.as_raw().as_slice().into()
+        }
+    }
+
+    /// Converts the image to 8-bit RGBA for the image crate
+    ///
+    /// Gray is spread over the red, green and blue channels, images without
+    /// alpha become opaque and 16-bit samples are rounded to the closest 8-bit
+    /// value. 8-bit RGBA images are moved without copying their pixels.
+    #[cfg(feature = "image")]
+    pub fn into_rgba_image(self) -> image::RgbaImage {
+        let img = match self.color_type() {
+            ColorType::Rgba => self,
+            _ => self.to_color_type(ColorType::Rgba)
+        };
+
+        let data = match img.depth {
+            BitDepth::Eight => img.data.into_vec(),
+            BitDepth::Sixteen => img.data.chunks(2).map(|sample| {
+                ((read_sample(sample) * 255 + 32767) / 65535) as u8
+            }).collect()
+        };
+
+        image::RgbaImage::from_raw(img.width as u32, img.height as u32, data).expect("every pixel has 4 samples")
+    }
+}
+
+/// Moves the pixels of an 8-bit RGBA image of the image crate, see
+/// [`Image::from_rgba_image`]
+#[cfg(feature = "image")]
+impl From<image::RgbaImage> for Image {
+    fn from(img: image::RgbaImage) -> Self {
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        Image { width, height, channels: 4, depth: BitDepth::Eight, data: img.into_raw().into_boxed_slice() }
+    }
+}
+
+/// An image whose pixels are indices into a palette of up to 256 colors, see
+/// [`Image::to_indexed`], [`Image::to_palette`] and [`Image::quantize`]
+#[derive(Clone)]
+pub struct IndexedImage {
+    pub width: usize,
+    pub height: usize,
+
+    /// The 8-bit RGBA colors the indices refer to
+    pub palette: Vec<[u8; 4]>,
+
+    /// One index into the palette per pixel, in row-major order
+    pub indices: Box<[u8]>
+}
+
+// The source pixels that make up every pixel along an axis scaled from `src`
+// to `dst` pixels, as the first one and their weights. The triangle filter is
+// stretched over the pixels covered when shrinking
+fn triangle_weights(src: usize, dst: usize) -> Vec<(usize, Vec<f64>)> {
+    let scale = src as f64 / dst as f64;
+    let radius = scale.max(1.0);
+
+    (0..dst).map(|i| {
+        let center = (i as f64 + 0.5) * scale;
+        let start = (center - radius).floor().max(0.0) as usize;
+        let end = ((center + radius).ceil() as usize).min(src);
+
+        // The closest source pixel is always within the radius, so the total
+        // is never 0
+        let mut weights: Vec<f64> = (start..end)
+            .map(|j| (1.0 - (j as f64 + 0.5 - center).abs() / radius).max(0.0))
+            .collect();
+        let total: f64 = weights.iter().sum();
+        weights.iter_mut().for_each(|weight| *weight /= total);
+
+        (start, weights)
+    }).collect()
+}
+
+// Scales the rows of an image from `src` to `dst` pixels, or its columns if
+// `rows` is false. There are `lines` rows or columns
+fn resample(samples: &[f64], channels: usize, src: usize, dst: usize, lines: usize, rows: bool) -> Vec<f64> {
+    let weights = triangle_weights(src, dst);
+    let mut out = vec![0.0; dst * lines * channels];
+
+    for line in 0..lines {
+        // Where the line starts in the source and in the result, and how far
+        // apart its pixels are
+        let (src_at, dst_at, stride) = match rows {
+            true => (line * src, line * dst, 1),
+            false => (line, line, lines)
+        };
+
+        for (i, (start, weights)) in weights.iter().enumerate() {
+            let out_idx = (dst_at + i * stride) * channels;
+            for (j, weight) in weights.iter().enumerate() {
+                let idx = (src_at + (start + j) * stride) * channels;
+                for k in 0..channels {
+                    out[out_idx + k] += samples[idx + k] * weight;
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// The peak signal-to-noise ratio between two images in decibels, over every
+/// channel
+///
+/// The higher, the closer the images are, and it is infinite when they are
+/// identical. Like the tolerance, the error is measured on channels from 0 to
+/// 255 whatever the depth of the images.
+///
+/// # Panics
+///
+/// If the images don't have the same size, channels and depth.
+pub fn psnr(a: &Image, b: &Image) -> f64 {
+    assert!(
+        a.width == b.width && a.height == b.height && a.channels == b.channels && a.depth == b.depth,
+        "the images must have the same size, channels and depth"
+    );
+
+    let size = a.depth.bytes();
+    let error: u128 = a.data.chunks(size).zip(b.data.chunks(size)).map(|(a, b)| {
+        let diff = read_sample(a).abs_diff(read_sample(b)) as u128;
+        diff * diff
+    }).sum();
+
+    let samples = a.width * a.height * a.channels;
+    if samples == 0 {
+        return f64::INFINITY;
+    }
+
+    let mse = error as f64 / samples as f64 / a.depth.variance_scale() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+// Rec. 601 luma from 0 to 255 of a gray level or an RGB color
+fn luma(color: &[u64], depth: BitDepth) -> f64 {
+    let luma = match *color {
+        [l] => l as f64,
+        [r, g, b] => 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64,
+        _ => unreachable!()
+    };
+
+    luma * 255.0 / depth.max_value() as f64
+}
+
+// The luma of every pixel, ignoring the alpha channel
+fn image_luma(img: &Image) -> Vec<f64> {
+    let color_len = if img.color_type().is_gray() { 1 } else { 3 };
+    let size = img.depth.bytes();
+    img.pixels().map(|pixel| {
+        let mut color = [0; 3];
+        for (c, sample) in color.iter_mut().zip(pixel.chunks(size).take(color_len)) {
+            *c = read_sample(sample);
+        }
+
+        luma(&color[..color_len], img.depth)
+    }).collect()
+}
+
+// The linear intensity from 0 to 1 of an sRGB channel from 0 to 1
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// The sRGB channel from 0 to 1 of a linear intensity from 0 to 1
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// The linear intensity of every value of a channel of the given depth, so the
+// transfer function isn't computed again for every sample
+fn linear_table(depth: BitDepth) -> Vec<f32> {
+    let max = depth.max_value();
+    (0..=max).map(|c| srgb_to_linear(c as f32 / max as f32)).collect()
+}
+
+// The image in linear light, as 16-bit channels so that it can be averaged
+// with a summed-area table. Alpha isn't gamma-encoded, it is only scaled, and
+// the colors are multiplied by it if `premultiply` is set
+fn linear_image(img: &Image, premultiply: bool) -> Image {
+    let linear = linear_table(img.depth);
+    let color_len = if img.color_type().is_gray() { 1 } else { 3 };
+    let size = img.depth.bytes();
+    let max = img.depth.max_value();
+
+    let mut data = Vec::with_capacity(img.data.len() / size * 2);
+    for pixel in img.pixels() {
+        let coverage = alpha_coverage(img, pixel, premultiply);
+        for (k, sample) in pixel.chunks(size).enumerate() {
+            let value = read_sample(sample);
+            let value = if k < color_len {
+                (linear[value as usize] * coverage * 65535.0).round() as u16
+            } else {
+                (value * 65535 / max) as u16
+            };
+
+            data.extend(value.to_be_bytes());
+        }
+    }
+
+    Image {
+        width: img.width,
+        height: img.height,
+        channels: img.channels,
+        depth: BitDepth::Sixteen,
+        data: data.into()
+    }
+}
+
+// The alpha of a pixel from 0 to 1 if the colors are premultiplied by it, and 1
+// otherwise
+fn alpha_coverage(img: &Image, pixel: &[u8], premultiply: bool) -> f32 {
+    if !premultiply || !img.color_type().has_alpha() {
+        return 1.0;
+    }
+
+    let size = img.depth.bytes();
+    read_sample(&pixel[pixel.len() - size..]) as f32 / img.depth.max_value() as f32
+}
+
+// The image with its colors multiplied by its alpha, in the same depth. Fully
+// transparent pixels become black whatever their color was
+fn premultiplied_image(img: &Image) -> Image {
+    let size = img.depth.bytes();
+    let max = img.depth.max_value();
+    let pixel_len = img.channels * size;
+
+    let mut data = img.data.to_vec();
+    for pixel in data.chunks_mut(pixel_len) {
+        let (color, alpha) = pixel.split_at_mut(pixel_len - size);
+        let alpha = read_sample(alpha);
+        for sample in color.chunks_mut(size) {
+            let value = (read_sample(sample) * alpha + max / 2) / max;
+            write_sample(value, sample);
+        }
+    }
+
+    Image {
+        width: img.width,
+        height: img.height,
+        channels: img.channels,
+        depth: img.depth,
+        data: data.into()
+    }
+}
+
+// CIELAB of a linear RGB color, under the D65 white point of sRGB
+fn linear_to_lab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| if t > 216.0 / 24389.0 {
+        t.cbrt()
+    } else {
+        (24389.0 / 27.0 * t + 16.0) / 116.0
+    };
+
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Converts an 8-bit sRGB color to CIELAB under the D65 white point, with `L`
+/// from 0 to 100 and `a` and `b` roughly from -128 to 127
+pub fn srgb_to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    linear_to_lab(rgb.map(|c| srgb_to_linear(c as f32 / 255.0)))
+}
+
+// The channels of `lab_image` are multiples of 1/64
+const LAB_SCALE: u64 = 64;
+
+// The image in CIELAB, with 16-bit fixed-point channels so that its variance
+// can be measured with a summed-area table. `a` and `b` are offset by 128 to
+// stay positive, gray images only keep `L` and alpha goes from 0 to 255. The
+// colors are multiplied by the alpha before the offset if `premultiply` is set,
+// so that transparent pixels are neutral
+fn lab_image(img: &Image, premultiply: bool) -> Image {
+    let max = img.depth.max_value();
+    let linear = linear_table(img.depth);
+    let color_len = if img.color_type().is_gray() { 1 } else { 3 };
+    let size = img.depth.bytes();
+
+    let fixed = |value: f32| (value * LAB_SCALE as f32).round().clamp(0.0, 65535.0) as u16;
+    let mut data = Vec::with_capacity(img.data.len() / size * 2);
+    for pixel in img.pixels() {
+        let mut samples = [0; 4];
+        for (value, sample) in samples.iter_mut().zip(pixel.chunks(size)) {
+            *value = read_sample(sample);
+        }
+
+        let (color, alpha) = samples[..img.channels].split_at(color_len);
+        let rgb = match *color {
+            [l] => [l; 3],
+            [r, g, b] => [r, g, b],
+            _ => unreachable!()
+        };
+
+        let coverage = alpha_coverage(img, pixel, premultiply);
+        let [l, a, b] = linear_to_lab(rgb.map(|c| linear[c as usize])).map(|c| c * coverage);
+        data.extend(fixed(l).to_be_bytes());
+        if color_len == 3 {
+            data.extend(fixed(a + 128.0).to_be_bytes());
+            data.extend(fixed(b + 128.0).to_be_bytes());
+        }
+
+        for &alpha in alpha {
+            let alpha = (alpha * 255 * LAB_SCALE + max / 2) / max;
+            data.extend((alpha as u16).to_be_bytes());
+        }
+    }
+
+    Image {
+        width: img.width,
+        height: img.height,
+        channels: img.channels,
+        depth: BitDepth::Sixteen,
+        data: data.into()
+    }
+}
+
+// The Rec. 709 luminance of the image, as a single 16-bit channel so that its
+// variance can be measured with a summed-area table. It is multiplied by the
+// alpha if `premultiply` is set, and alpha is left out otherwise
+fn luma_image(img: &Image, premultiply: bool) -> Image {
+    let color_len = if img.color_type().is_gray() { 1 } else { 3 };
+    let size = img.depth.bytes();
+    let scale = 65535.0 / img.depth.max_value() as f32;
+
+    let mut data = Vec::with_capacity(img.width * img.height * 2);
+    for pixel in img.pixels() {
+        let mut color = [0.0; 3];
+        for (c, sample) in color.iter_mut().zip(pixel.chunks(size).take(color_len)) {
+            *c = read_sample(sample) as f32;
+        }
+
+        let luma = match color_len {
+            1 => color[0],
+            _ => 0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+        };
+
+        let luma = luma * scale * alpha_coverage(img, pixel, premultiply);
+        data.extend((luma.round().clamp(0.0, 65535.0) as u16).to_be_bytes());
+    }
+
+    Image {
+        width: img.width,
+        height: img.height,
+        channels: 1,
+        depth: BitDepth::Sixteen,
+        data: data.into()
+    }
+}
+
+// The side of the Gaussian windows SSIM is computed over, and their standard
+// deviation, as in the reference implementation
+const SSIM_WINDOW: usize = 11;
+const SSIM_SIGMA: f64 = 1.5;
+
+// The weights of a Gaussian window of `len` samples, which add up to 1
+fn gaussian_window(len: usize) -> Vec<f64> {
+    let center = (len - 1) as f64 / 2.0;
+    let weights: Vec<f64> = (0..len)
+        .map(|i| (-(i as f64 - center).powi(2) / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp())
+        .collect();
+
+    let total: f64 = weights.iter().sum();
+    weights.into_iter().map(|w| w / total).collect()
+}
+
+// The mean SSIM over every 11x11 Gaussian window that fits in two luma planes.
+// The window is separable, so the weighted sums of a, b, a^2, b^2 and a * b
+// are taken along the rows first and then along the columns
+fn mean_ssim(width: usize, height: usize, a: &[f64], b: &[f64]) -> f64 {
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    // Images smaller than a window are compared with a window of their size
+    let window_x = gaussian_window(width.min(SSIM_WINDOW));
+    let window_y = gaussian_window(height.min(SSIM_WINDOW));
+    let out_width = width - window_x.len() + 1;
+    let out_height = height - window_y.len() + 1;
+
+    let mut rows = vec![[0.0; 5]; height * out_width];
+    for y in 0..height {
+        for x in 0..out_width {
+            let sums = &mut rows[y * out_width + x];
+            for (i, &w) in window_x.iter().enumerate() {
+                let (p, q) = (a[y * width + x + i], b[y * width + x + i]);
+                for (sum, value) in sums.iter_mut().zip([p, q, p * p, q * q, p * q]) {
+                    *sum += w * value;
+                }
+            }
+        }
+    }
+
+    let mut total = 0.0;
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let mut sums = [0.0; 5];
+            for (j, &w) in window_y.iter().enumerate() {
+                for (sum, value) in sums.iter_mut().zip(rows[(y + j) * out_width + x]) {
+                    *sum += w * value;
+                }
+            }
+
+            let (mean_a, mean_b) = (sums[0], sums[1]);
+            let var_a = sums[2] - mean_a * mean_a;
+            let var_b = sums[3] - mean_b * mean_b;
+            let cov = sums[4] - mean_a * mean_b;
+
+            total += (2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2) /
+                ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+        }
+    }
+
+    total / (out_width * out_height) as f64
+}
+
+/// The structural similarity between two images, from 0 to 1 where 1 means
+/// they are identical
+///
+/// It is the mean SSIM over every 11x11 Gaussian window (with a standard
+/// deviation of 1.5 pixels) of the luma of the images, like the reference
+/// implementation, so unlike [`psnr`] it notices the edges between flat
+/// blocks. The alpha channel is ignored, and the images may have different
+/// channels and depths.
+///
+/// # Panics
+///
+/// If the images don't have the same size.
+pub fn ssim(a: &Image, b: &Image) -> f64 {
+    assert!(a.width == b.width && a.height == b.height, "the images must have the same size");
+    mean_ssim(a.width, a.height, &image_luma(a), &image_luma(b))
+}
+
+/// Why the data of an image couldn't be decoded, see [`PngpartError::Decode`]
+#[derive(Debug)]
+pub enum ImageError {
+    /// The data couldn't be read from its source
+    Io(std::io::Error),
+
+    /// The data is not a valid PNG
+    Decode(png::DecodingError),
+
+    /// The data starts like a JPEG but is not a valid one
+    #[cfg(feature = "jpeg")]
+    Jpeg(jpeg_decoder::Error)
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Decode(err) => write!(f, "{err}"),
+            #[cfg(feature = "jpeg")]
+            Self::Jpeg(err) => write!(f, "{err}")
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Decode(err) => Some(err),
+            #[cfg(feature = "jpeg")]
+            Self::Jpeg(err) => Some(err)
+        }
+    }
+}
+
+impl From<std::io::Error> for ImageError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(err: png::DecodingError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+#[cfg(feature = "jpeg")]
+impl From<jpeg_decoder::Error> for ImageError {
+    fn from(err: jpeg_decoder::Error) -> Self {
+        Self::Jpeg(err)
+    }
+}
+
+// The start of image marker followed by the start of another marker
+const JPEG_SIGNATURE: [u8; 3] = [0xff, 0xd8, 0xff];
+
+/// Whether `bytes` start like a JPEG, which [`decode_image`] decodes with the
+/// `jpeg` feature
+pub fn is_jpeg(bytes: &[u8]) -> bool {
+    bytes.starts_with(&JPEG_SIGNATURE)
+}
+
+/// Reads a PNG file, see [`decode_image`]
+pub fn read_image(path: &Path) -> Result<Image, PngpartError> {
+    let file = File::open(path).map_err(|source| PngpartError::Io { path: path.to_path_buf(), source })?;
+    decode_image(file)
+}
+
+/// The palette of the PNG in `input` as 8-bit RGBA colors, or `None` if its
+/// pixels aren't indices into one
+///
+/// [`decode_image`] expands the palette into the colors themselves, this only
+/// reads the chunks before the image data.
+pub fn read_palette(input: impl Read) -> Result<Option<Vec<[u8; 4]>>, ImageError> {
+    let reader = png::Decoder::new(input).read_info()?;
+    let info = reader.info();
+    let palette = match &info.palette {
+        Some(palette) if info.color_type == png::ColorType::Indexed => palette,
+        _ => return Ok(None)
+    };
+
+    // Colors past the end of the transparency chunk are opaque
+    let alpha = info.trns.as_deref().unwrap_or_default();
+    Ok(Some(palette.chunks_exact(3).enumerate().map(|(i, rgb)| {
+        [rgb[0], rgb[1], rgb[2], alpha.get(i).copied().unwrap_or(0xff)]
+    }).collect()))
+}
+
+/// Decodes a PNG from `input`, keeping it in grayscale if it has no colors
+/// and adding an alpha channel only if it has transparency
+///
+/// 16-bit images keep their depth, every other image is converted to 8-bit.
+/// With the `jpeg` feature, JPEGs are decoded the same way. The format is told
+/// by the first bytes, whatever the name of the file.
+pub fn decode_image(input: impl Read) -> Result<Image, PngpartError> {
+    // The first bytes are put back in front of the rest once they are read
+    #[cfg(feature = "jpeg")]
+    let input = {
+        let mut input = input;
+        let mut magic = Vec::new();
+        (&mut input).take(JPEG_SIGNATURE.len() as u64).read_to_end(&mut magic).map_err(ImageError::Io)?;
+
+        let jpeg = is_jpeg(&magic);
+        let input = std::io::Cursor::new(magic).chain(input);
+        if jpeg {
+            return Ok(decode_jpeg(input)?);
+        }
+
+        input
+    };
+
+    let mut decoder = png::Decoder::new(input);
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    Ok(next_image(&mut decoder.read_info().map_err(ImageError::Decode)?)?)
+}
+
+// Decodes a JPEG, keeping it in grayscale if it has no colors. CMYK images are
+// converted to RGB without their color profile
+#[cfg(feature = "jpeg")]
+fn decode_jpeg(input: impl Read) -> Result<Image, ImageError> {
+    use jpeg_decoder::PixelFormat;
+
+    let mut decoder = jpeg_decoder::Decoder::new(std::io::BufReader::new(input));
+    let pixels = decoder.decode()?;
+
+    // The header is always read before the pixels
+    let info = decoder.info().expect("the header was decoded");
+    let (channels, depth, data) = match info.pixel_format {
+        PixelFormat::L8 => (1, BitDepth::Eight, pixels),
+        // 16-bit samples are decoded in native order
+        PixelFormat::L16 => (1, BitDepth::Sixteen, pixels.chunks_exact(2).flat_map(|sample| {
+            u16::from_ne_bytes([sample[0], sample[1]]).to_be_bytes()
+        }).collect()),
+        PixelFormat::RGB24 => (3, BitDepth::Eight, pixels),
+        PixelFormat::CMYK32 => (3, BitDepth::Eight, pixels.chunks_exact(4).flat_map(|cmyk| {
+            let white = 255 - cmyk[3] as u32;
+            [0, 1, 2].map(|k| ((255 - cmyk[k] as u32) * white / 255) as u8)
+        }).collect())
+    };
+
+    Ok(Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        channels,
+        depth,
+        data: data.into()
+    })
+}
+
+// Decodes the next frame of `reader`, which only covers part of the image if
+// it is a frame of an animation
+fn next_image(reader: &mut png::Reader<impl Read>) -> Result<Image, ImageError> {
+    let mut buf = vec![0u8; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.resize(info.buffer_size(), 0);
+
+    // Depths below 8 bits are already expanded
+    let depth = match info.bit_depth {
+        png::BitDepth::Sixteen => BitDepth::Sixteen,
+        _ => BitDepth::Eight
+    };
+
+    // Palettes and transparent colors are already expanded
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        _ => 4
+    };
+
+    Ok(Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        channels,
+        depth,
+        data: buf.into()
+    })
+}
+
+/// What happens to the area of a [`Frame`] before the next one is drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dispose {
+    /// Leave the frame as it is
+    None,
+
+    /// Clear the area to transparent black
+    Background,
+
+    /// Restore the area to what it was before the frame was drawn
+    Previous
+}
+
+/// How a [`Frame`] is drawn over what is already there
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blend {
+    /// Replace the pixels, including their alpha
+    Source,
+
+    /// Composite the frame over the pixels by its alpha
+    Over
+}
+
+/// A frame of an [`Animation`]
+#[derive(Clone)]
+pub struct Frame {
+    /// The pixels of the frame, which may only cover part of the animation
+    pub image: Image,
+
+    /// Where the top left corner of the frame is drawn
+    pub x: usize,
+    pub y: usize,
+
+    /// How long the frame is shown, in seconds as a numerator and a
+    /// denominator. A denominator of 0 means hundredths of a second
+    pub delay: (u16, u16),
+
+    pub dispose: Dispose,
+    pub blend: Blend
+}
+
+/// The frames of an animated PNG
+///
+/// Every frame has the channels and depth of the whole animation.
+#[derive(Clone)]
+pub struct Animation {
+    pub width: usize,
+    pub height: usize,
+
+    /// How many times the animation is played, 0 is forever
+    pub plays: u32,
+
+    /// The image shown by viewers that don't support animations, if it isn't
+    /// the first frame
+    pub default_image: Option<Image>,
+
+    pub frames: Vec<Frame>
+}
+
+/// Decodes every frame of an animated PNG from `input`, in the same color type
+/// as [`decode_image`]
+///
+/// Returns `None` if the PNG isn't animated. The frames aren't composited, each
+/// of them holds the pixels it is drawn with.
+pub fn decode_animation(input: impl Read) -> Result<Option<Animation>, ImageError> {
+    let mut decoder = png::Decoder::new(input);
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    let Some(control) = info.animation_control else {
+        return Ok(None);
+    };
+    let (width, height) = (info.width as usize, info.height as usize);
+
+    // The image data is only the first frame if it has its own frame control
+    let default_image = match info.frame_control {
+        Some(_) => None,
+        None => Some(next_image(&mut reader)?)
+    };
+
+    let mut frames = Vec::with_capacity(control.num_frames as usize);
+    for _ in 0..control.num_frames {
+        let image = next_image(&mut reader)?;
+
+        // The frame control of a frame is read along with it
+        let control = reader.info().frame_control.unwrap_or_default();
+        frames.push(Frame {
+            image,
+            x: control.x_offset as usize,
+            y: control.y_offset as usize,
+            delay: (control.delay_num, control.delay_den),
+            dispose: match control.dispose_op {
+                png::DisposeOp::None => Dispose::None,
+                png::DisposeOp::Background => Dispose::Background,
+                png::DisposeOp::Previous => Dispose::Previous
+            },
+            blend: match control.blend_op {
+                png::BlendOp::Source => Blend::Source,
+                png::BlendOp::Over => Blend::Over
+            }
+        });
+    }
+
+    Ok(Some(Animation { width, height, plays: control.num_plays, default_image, frames }))
+}
+
+/// Why an image couldn't be encoded
+#[derive(Debug)]
+pub enum EncodeError {
+    /// The PNG header couldn't be generated
+    Header(png::EncodingError),
+
+    /// The pixels couldn't be encoded
+    Encode(png::EncodingError),
+
+    /// A frame of the GIF couldn't be encoded
+    Gif(gif::EncodingError),
+
+    /// The image is larger than a GIF can be
+    TooLarge(usize, usize)
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Header(err) => write!(f, "{err}"),
+            Self::Encode(err) => write!(f, "{err}"),
+            Self::Gif(err) => write!(f, "{err}"),
+            Self::TooLarge(width, height) => write!(f, "{width}x{height} is larger than a GIF can be")
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Header(err) => Some(err),
+            Self::Encode(err) => Some(err),
+            Self::Gif(err) => Some(err),
+            Self::TooLarge(..) => None
+        }
+    }
+}
+
+/// Why reading, decoding, encoding or optimizing an image failed
+#[derive(Debug)]
+pub enum PngpartError {
+    /// The image couldn't be decoded
+    Decode(ImageError),
+
+    /// The image couldn't be encoded
+    Encode(EncodeError),
+
+    /// The file at `path` couldn't be accessed
+    Io { path: PathBuf, source: std::io::Error },
+
+    /// Oxipng failed to optimize the encoded PNG
+    Optimize(oxipng::PngError),
+
+    /// The image can't be encoded as it is, such as when it has no pixels
+    InvalidInput(String)
+}
+
+impl std::fmt::Display for PngpartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "{err}"),
+            Self::Encode(err) => write!(f, "{err}"),
+            Self::Io { path, source } => write!(f, "`{}`: {source}", path.display()),
+            Self::Optimize(err) => write!(f, "{err}"),
+            Self::InvalidInput(reason) => write!(f, "{reason}")
+        }
+    }
+}
+
+impl std::error::Error for PngpartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(err) => Some(err),
+            Self::Encode(err) => Some(err),
+            Self::Io { source, .. } => Some(source),
+            Self::Optimize(err) => Some(err),
+            Self::InvalidInput(_) => None
+        }
+    }
+}
+
+impl From<ImageError> for PngpartError {
+    fn from(err: ImageError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl From<EncodeError> for PngpartError {
+    fn from(err: EncodeError) -> Self {
+        Self::Encode(err)
+    }
+}
+
+/// The oxipng preset used when no optimization level is given
+pub const DEFAULT_OPT_LEVEL: u8 = 2;
+
+/// Which ancillary chunks oxipng removes from the optimized images
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StripMode {
+    /// Keep every chunk
+    None,
+
+    /// Remove the chunks that don't affect how the image is displayed, but
+    /// keep color profiles and the physical size
+    Safe,
+
+    /// Remove every ancillary chunk, including text and timestamps, but keep
+    /// the frames of animations
+    All
+}
+
+/// How oxipng optimizes the encoded images
+#[derive(Clone, Debug)]
+pub struct OptimizeOptions {
+    /// The oxipng preset, from 0 (fastest) to 6 (smallest). Levels above 6
+    /// are treated as 6
+    pub level: u8,
+
+    /// Compress with Zopfli and this many iterations instead of libdeflate,
+    /// which is smaller but much slower
+    pub zopfli: Option<NonZeroU8>,
+
+    /// Which ancillary chunks are removed, see [`StripMode`]
+    pub strip: StripMode,
+
+    /// Interlace the image with Adam7 so it can be displayed progressively,
+    /// which makes it larger. The encoder can't interlace images, so oxipng
+    /// is the only way to do it
+    pub interlace: bool,
+
+    /// Keep the color type of the encoded image, instead of letting oxipng
+    /// reduce it when that doesn't lose information
+    pub keep_color_type: bool
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self { level: DEFAULT_OPT_LEVEL, zopfli: None, strip: StripMode::None, interlace: false, keep_color_type: false }
+    }
+}
+
+/// Encodes an image to PNG and optimizes it with oxipng
+pub fn encode_png(img: &Image) -> Result<Vec<u8>, PngpartError> {
+    optimize_png(&encode_png_fast(img)?, &OptimizeOptions::default())
+}
+
+/// How hard the PNG encoder compresses the image data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PngCompression {
+    /// Barely compress, which is enough when oxipng optimizes the image
+    Fast,
+
+    /// The encoder's default compression
+    Default,
+
+    /// Compress as much as the encoder can, for when oxipng is skipped
+    Best
+}
+
+impl PngCompression {
+    fn png(self) -> png::Compression {
+        match self {
+            Self::Fast => png::Compression::Fast,
+            Self::Default => png::Compression::Default,
+            Self::Best => png::Compression::Best
+        }
+    }
+}
+
+/// Encodes an image to PNG as fast as possible, without optimizing it
+pub fn encode_png_fast(img: &Image) -> Result<Vec<u8>, PngpartError> {
+    encode_png_with(img, PngCompression::Fast)
+}
+
+/// Encodes an image to PNG with the given compression, without optimizing it
+///
+/// Images without pixels can't be PNGs, they are rejected with
+/// [`PngpartError::InvalidInput`].
+pub fn encode_png_with(img: &Image, compression: PngCompression) -> Result<Vec<u8>, PngpartError> {
+    if img.width == 0 || img.height == 0 {
+        return Err(PngpartError::InvalidInput(format!("a {}x{} image has no pixels", img.width, img.height)));
+    }
+
+    let w = img.width as u32;
+    let h = img.height as u32;
+    let buf = &img.data as &[u8];
+
+    let mut out_buf = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(BufWriter::new(&mut out_buf), w, h);
+        encoder.set_color(match img.color_type() {
+            ColorType::Gray => png::ColorType::Grayscale,
+            ColorType::GrayAlpha => png::ColorType::GrayscaleAlpha,
+            ColorType::Rgb => png::ColorType::Rgb,
+            ColorType::Rgba => png::ColorType::Rgba
+        });
+        encoder.set_depth(match img.depth {
+            BitDepth::Eight => png::BitDepth::Eight,
+            BitDepth::Sixteen => png::BitDepth::Sixteen
+        });
+        encoder.set_compression(compression.png());
+
+        let mut writer = encoder.write_header().map_err(EncodeError::Header)?;
+        writer.write_image_data(buf).map_err(EncodeError::Encode)?;
+    }
+
+    Ok(out_buf)
+}
+
+/// Encodes an animation to an APNG with the given compression, without
+/// optimizing it
+///
+/// The frames are written with their position, delay, disposal and blending.
+/// Animations without frames can't be encoded.
+pub fn encode_animation(animation: &Animation, compression: PngCompression) -> Result<Vec<u8>, PngpartError> {
+    let mut out_buf = Vec::new();
+
+    {
+        let w = animation.width as u32;
+        let h = animation.height as u32;
+        let mut encoder = png::Encoder::new(BufWriter::new(&mut out_buf), w, h);
+
+        // Frames always have the color type of the whole animation
+        if let Some(first) = animation.default_image.as_ref().or(animation.frames.first().map(|frame| &frame.image)) {
+            encoder.set_color(match first.color_type() {
+                ColorType::Gray => png::ColorType::Grayscale,
+                ColorType::GrayAlpha => png::ColorType::GrayscaleAlpha,
+                ColorType::Rgb => png::ColorType::Rgb,
+                ColorType::Rgba => png::ColorType::Rgba
+            });
+            encoder.set_depth(match first.depth {
+                BitDepth::Eight => png::BitDepth::Eight,
+                BitDepth::Sixteen => png::BitDepth::Sixteen
+            });
+        }
+        encoder.set_compression(compression.png());
+        encoder.set_animated(animation.frames.len() as u32, animation.plays).map_err(EncodeError::Header)?;
+        encoder.set_sep_def_img(animation.default_image.is_some()).map_err(EncodeError::Header)?;
+
+        let mut writer = encoder.write_header().map_err(EncodeError::Header)?;
+        if let Some(img) = &animation.default_image {
+            writer.write_image_data(&img.data).map_err(EncodeError::Encode)?;
+        }
+
+        for frame in &animation.frames {
+            // The position is checked against the size of the previous frame
+            writer.reset_frame_position().map_err(EncodeError::Encode)?;
+            writer.set_frame_dimension(frame.image.width as u32, frame.image.height as u32)
+                .map_err(EncodeError::Encode)?;
+            writer.set_frame_position(frame.x as u32, frame.y as u32).map_err(EncodeError::Encode)?;
+            writer.set_frame_delay(frame.delay.0, frame.delay.1).map_err(EncodeError::Encode)?;
+            writer.set_dispose_op(match frame.dispose {
+                Dispose::None => png::DisposeOp::None,
+                Dispose::Background => png::DisposeOp::Background,
+                Dispose::Previous => png::DisposeOp::Previous
+            }).map_err(EncodeError::Encode)?;
+            writer.set_blend_op(match frame.blend {
+                Blend::Source => png::BlendOp::Source,
+                Blend::Over => png::BlendOp::Over
+            }).map_err(EncodeError::Encode)?;
+            writer.write_image_data(&frame.image.data).map_err(EncodeError::Encode)?;
+        }
+
+        writer.finish().map_err(EncodeError::Encode)?;
+    }
+
+    Ok(out_buf)
+}
+
+/// Encodes an indexed image to an 8-bit palette PNG with the given
+/// compression, without optimizing it
+///
+/// The alpha of the palette is only written if a color isn't opaque.
+pub fn encode_indexed_png(img: &IndexedImage, compression: PngCompression) -> Result<Vec<u8>, PngpartError> {
+    let mut out_buf = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(BufWriter::new(&mut out_buf), img.width as u32, img.height as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(img.palette.iter().flat_map(|color| &color[..3]).copied().collect::<Vec<_>>());
+        if img.palette.iter().any(|color| color[3] < 0xff) {
+            encoder.set_trns(img.palette.iter().map(|color| color[3]).collect::<Vec<_>>());
+        }
+        encoder.set_compression(compression.png());
+
+        let mut writer = encoder.write_header().map_err(EncodeError::Header)?;
+        writer.write_image_data(&img.indices).map_err(EncodeError::Encode)?;
+    }
+
+    Ok(out_buf)
+}
+
+/// Optimizes an encoded PNG with oxipng
+pub fn optimize_png(png: &[u8], options: &OptimizeOptions) -> Result<Vec<u8>, PngpartError> {
+    let mut oxipng_options = oxipng::Options::from_preset(options.level);
+    if let Some(iterations) = options.zopfli {
+        oxipng_options.deflate = oxipng::Deflaters::Zopfli { iterations };
+    }
+
+    oxipng_options.strip = match options.strip {
+        StripMode::None => oxipng::StripChunks::None,
+        StripMode::Safe => oxipng::StripChunks::Safe,
+        // The chunks of APNGs hold their frames rather than metadata
+        StripMode::All => oxipng::StripChunks::Keep(oxipng::indexset! { *b"acTL", *b"fcTL", *b"fdAT" })
+    };
+
+    // Oxipng removes the interlacing unless it is asked to keep it
+    if options.interlace {
+        oxipng_options.interlace = Some(oxipng::Interlacing::Adam7);
+    }
+
+    if options.keep_color_type {
+        oxipng_options.color_type_reduction = false;
+        oxipng_options.grayscale_reduction = false;
+        oxipng_options.palette_reduction = false;
+    }
+
+    oxipng::optimize_from_memory(png, &oxipng_options).map_err(PngpartError::Optimize)
+}
+
+/// Encodes images of the same size as the frames of an animated GIF, which
+/// loops forever
+///
+/// The frames are encoded as they are added, so that they don't all have to be
+/// kept around. GIF colors are 8-bit and come from a palette of 256 of them,
+/// frames with more colors are quantized and alpha is either fully opaque or
+/// fully transparent.
+pub struct GifEncoder {
+    encoder: gif::Encoder<Vec<u8>>,
+    delay: u16
+}
+
+impl GifEncoder {
+    /// Starts a GIF of `width` by `height` pixels, which shows every frame for
+    /// `delay`, rounded to hundredths of a second
+    pub fn new(width: usize, height: usize, delay: Duration) -> Result<Self, EncodeError> {
+        let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err(EncodeError::TooLarge(width, height));
+        };
+
+        let mut encoder = gif::Encoder::new(Vec::new(), w, h, &[]).map_err(EncodeError::Gif)?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(EncodeError::Gif)?;
+        let delay = (delay.as_millis() + 5) / 10;
+
+        Ok(Self { encoder, delay: delay.try_into().unwrap_or(u16::MAX) })
+    }
+
+    /// Adds `img` as the next frame, it must be as large as the GIF
+    pub fn add_frame(&mut self, img: &Image) -> Result<(), EncodeError> {
+        let mut rgba = img.rgba8().concat();
+
+        let mut frame = gif::Frame::from_rgba_speed(img.width as u16, img.height as u16, &mut rgba, 10);
+        frame.delay = self.delay;
+
+        // Transparent pixels would show the previous frame otherwise
+        frame.dispose = gif::DisposalMethod::Background;
+        self.encoder.write_frame(&frame).map_err(EncodeError::Gif)
+    }
+
+    /// Ends the GIF and returns its bytes
+    pub fn finish(self) -> Result<Vec<u8>, EncodeError> {
+        self.encoder.into_inner().map_err(|err| EncodeError::Gif(err.into()))
+    }
+}
+
+/// An axis-aligned region of an image, the maximums are exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bound {
+    pub x_min: usize,
+    pub x_max: usize,
+    pub y_min: usize,
+    pub y_max: usize
+}
+
+impl Bound {
+    pub fn new(x_min: usize, x_max: usize, y_min: usize, y_max: usize) -> Self {
+        Self { x_min, x_max, y_min, y_max }
+    }
+
+    pub fn width(&self) -> usize {
+        self.x_max - self.x_min
+    }
+
+    pub fn height(&self) -> usize {
+        self.y_max - self.y_min
+    }
+
+    /// The number of pixels in the region
+    pub fn area(&self) -> usize {
+        self.width() * self.height()
+    }
+
+    /// Whether the regions have at least one pixel in common
+    pub fn intersects(&self, other: &Bound) -> bool {
+        self.x_min < other.x_max && other.x_min < self.x_max &&
+            self.y_min < other.y_max && other.y_min < self.y_max
+    }
+}
+
+/// A region of the image that needs more detail than the rest, see
+/// [`CompressorOptions::regions`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub bound: Bound,
+    pub tolerance: u64
+}
+
+// The mean and variance are computed once from the bound, which must never be
+// modified afterwards. Create a new item instead
+struct HeapItem {
+    // The variance that decides when the block is split, see `Metric`
+    var: u64,
+
+    // The squared error of the block once painted with its color, which is
+    // its variance around that color in the channels of the image
+    error: u64,
+
+    // The color the block is painted with, see `Aggregate`
+    color: [u64; 4],
+    depth: usize,
+
+    // What the variance is divided by before it's compared, which is the
+    // number of samples with `VarianceMode::Mean` and 1 otherwise, scaled by
+    // the depth of the image
+    weight: u64,
+
+    bound: Bound
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapItem {
+    // Compares the weighted variances without dividing, so no precision is
+    // lost with `VarianceMode::Mean`
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = self.var as u128 * other.weight as u128;
+        let rhs = other.var as u128 * self.weight as u128;
+        lhs.cmp(&rhs)
+    }
+}
+
+impl Eq for HeapItem {}
+
+impl HeapItem {
+    // The variance as it is compared to the tolerance, rounded up so that a
+    // block above the tolerance never appears to be within it
+    fn weighted_var(&self) -> u64 {
+        self.var.div_ceil(self.weight)
+    }
+
+    fn within(&self, tolerance: u64) -> bool {
+        self.var as u128 <= tolerance as u128 * self.weight as u128
+    }
+}
+
+/// Why [`Compressor::compress`] stopped splitting blocks
+pub enum StopReason {
+    /// Every block that can still be split is within the tolerance
+    Tolerance,
+
+    /// The image reached the requested number of blocks
+    MaxBlocks,
+
+    /// The reconstructed image reached the PSNR given to
+    /// [`CompressorOptions::target_psnr`]
+    TargetPsnr,
+
+    /// None of the blocks can be split any further
+    Exhausted,
+
+    /// The callback given to [`Compressor::compress_with`] or
+    /// [`Compressor::compress_until`] asked to stop
+    Interrupted,
+
+    /// The flag given to [`Compressor::compress_cancellable`] was set
+    Cancelled
+}
+
+/// Where a block was cut, in pixels from the left or the top of the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cut {
+    /// Into a left and a right part, at this column
+    X(usize),
+
+    /// Into a top and a bottom part, at this row
+    Y(usize),
+
+    /// Into four quadrants, at this column and row
+    Both(usize, usize)
+}
+
+impl Cut {
+    /// The parts `bound` is cut into, from left to right and then from top
+    /// to bottom
+    pub fn split(self, bound: &Bound) -> Vec<Bound> {
+        let Bound { x_min, x_max, y_min, y_max } = *bound;
+        match self {
+            Self::X(x) => vec![Bound::new(x_min, x, y_min, y_max), Bound::new(x, x_max, y_min, y_max)],
+            Self::Y(y) => vec![Bound::new(x_min, x_max, y_min, y), Bound::new(x_min, x_max, y, y_max)],
+            Self::Both(x, y) => vec![
+                Bound::new(x_min, x, y_min, y),
+                Bound::new(x, x_max, y_min, y),
+                Bound::new(x_min, x, y, y_max),
+                Bound::new(x, x_max, y, y_max)
+            ]
+        }
+    }
+}
+
+/// What a single step of [`Compressor::step`] did
+#[derive(Clone, Copy, Debug)]
+pub struct StepInfo {
+    /// The variance of the block, as it is compared to the tolerance
+    pub variance: u64,
+
+    /// Where the block was in the image
+    pub bound: Bound,
+
+    /// Where the block was cut
+    pub cut: Cut,
+
+    /// The number of blocks after the step
+    pub blocks: usize
+}
+
+/// A split made by [`Compressor::compress_with`], as told to its callback
+#[derive(Clone, Debug)]
+pub struct SplitEvent {
+    /// The number of splits made before this one
+    pub iteration: usize,
+
+    /// Where the block was in the image
+    pub parent: Bound,
+
+    /// The variance of the block, as it is compared to the tolerance
+    pub variance: u64,
+
+    /// Where the block was cut
+    pub cut: Cut,
+
+    /// The blocks it was split into, two or four of them, with their variances
+    pub children: Vec<(Bound, u64)>
+}
+
+/// How far a [`Compressor`] went, as told to the closure of
+/// [`Compressor::compress_until`]
+#[derive(Clone, Copy, Debug)]
+pub struct CompressorState {
+    /// The number of blocks
+    pub blocks: usize,
+
+    /// The number of splits made so far
+    pub splits: usize,
+
+    /// The largest variance among the blocks that can still be split, 0 if
+    /// none of them can
+    pub max_variance: u64,
+
+    /// The summed squared error of every channel of the reconstructed image
+    /// against the original, on channels from 0 to 255
+    pub squared_error: f64,
+
+    // The number of samples of the whole image, its channels times its pixels,
+    // which the squared error is averaged over
+    samples: usize
+}
+
+impl CompressorState {
+    /// The mean squared error of every channel, see
+    /// [`Compressor::mean_squared_error`]
+    pub fn mean_squared_error(&self) -> f64 {
+        match self.samples {
+            0 => 0.0,
+            samples => self.squared_error / samples as f64
+        }
+    }
+
+    /// The peak signal-to-noise ratio in decibels, see [`Compressor::psnr`]
+    pub fn psnr(&self) -> f64 {
+        10.0 * (255.0 * 255.0 / self.mean_squared_error()).log10()
+    }
+}
+
+/// A block of the partition, see [`Compressor::blocks`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Block {
+    /// Where the block is in the image
+    pub bound: Bound,
+
+    /// The color of the block as 8-bit RGBA. Gray is spread over the red,
+    /// green and blue channels, and blocks of images without alpha are
+    /// opaque. The colors of 16-bit images are rounded down
+    pub color: [u8; 4],
+
+    /// The color the block is painted with, one sample per channel of the
+    /// image at its depth like [`Canvas::pixel`]
+    pub samples: [u64; 4],
+
+    /// The variance of the block, as it is compared to the tolerance
+    pub variance: u64
+}
+
+// A block as `Compressor::to_json` writes it, with the short names of its
+// rectangle and its 8-bit color
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct BlockRecord {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct PartitionRecord {
+    width: usize,
+    height: usize,
+    blocks: Vec<BlockRecord>
+}
+
+/// The blocks of a compressed image, which are enough to paint it again
+/// without the original, see [`Compressor::partition`]
+///
+/// With the `serde` feature, it can be saved with [`Partition::to_writer`]
+/// and loaded back with [`Partition::from_reader`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Partition {
+    pub width: usize,
+    pub height: usize,
+
+    /// The number of channels of the image, like [`Image::channels`]
+    pub channels: usize,
+
+    pub depth: BitDepth,
+
+    /// Every block, sorted like [`Compressor::blocks`]
+    pub blocks: Vec<Block>
+}
+
+impl Partition {
+    /// Paints every block with its color, which gives the same image as
+    /// [`Compressor::render`] with [`MeanFill`]
+    ///
+    /// # Panics
+    ///
+    /// If a block is outside of the image, or the image has no channel or
+    /// more than 4.
+    pub fn render(&self) -> Image {
+        assert!((1..=4).contains(&self.channels), "an image has between 1 and 4 channels");
+
+        let mut data = vec![0; self.width * self.height * self.channels * self.depth.bytes()];
+        let mut canvas = Canvas { width: self.width, channels: self.channels, depth: self.depth, data: &mut data };
+        for block in &self.blocks {
+            let Bound { x_max, y_max, .. } = block.bound;
+            assert!(x_max <= self.width && y_max <= self.height, "the block is outside of the image");
+            MeanFill.fill(&mut canvas, &block.bound, &block.samples);
+        }
+
+        Image { width: self.width, height: self.height, channels: self.channels, depth: self.depth, data: data.into() }
+    }
+
+    /// Writes the partition as JSON
+    #[cfg(feature = "serde")]
+    pub fn to_writer(&self, writer: impl std::io::Write) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+
+    /// Reads a partition written by [`Partition::to_writer`]
+    ///
+    /// Partitions whose image has no channel or more than 4, or with blocks
+    /// outside of the image, are rejected so that they can be rendered.
+    #[cfg(feature = "serde")]
+    pub fn from_reader(reader: impl Read) -> serde_json::Result<Self> {
+        use serde::de::Error;
+
+        let partition: Self = serde_json::from_reader(reader)?;
+        if !(1..=4).contains(&partition.channels) {
+            return Err(serde_json::Error::custom(format!("an image can't have {} channels", partition.channels)));
+        }
+
+        let outside = partition.blocks.iter().find(|block| {
+            block.bound.x_min > block.bound.x_max || block.bound.y_min > block.bound.y_max
+                || block.bound.x_max > partition.width || block.bound.y_max > partition.height
+        });
+        if let Some(block) = outside {
+            return Err(serde_json::Error::custom(format!("the block at {:?} is outside of the image", block.bound)));
+        }
+
+        Ok(partition)
+    }
+}
+
+/// How the blocks of a [`Compressor`] ended up, see [`Compressor::block_stats`]
+pub struct BlockStats {
+    pub below_tolerance: usize,
+    pub above_tolerance: usize,
+    pub limited_by_size: usize,
+    pub limited_by_depth: usize
+}
+
+/// How the variance of a block is measured against the tolerance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VarianceMode {
+    /// The mean squared deviation of every channel of every pixel, which
+    /// gives the tolerance the same meaning for blocks of any size
+    Mean,
+
+    /// The summed squared deviation over the whole block, so larger blocks
+    /// are split before smaller ones with the same error
+    Total
+}
+
+impl VarianceMode {
+    fn weight(self, bound: &Bound, channels: usize, scale: u64) -> u64 {
+        match self {
+            Self::Mean => (channels * bound.area()) as u64 * scale,
+            Self::Total => scale
+        }
+    }
+}
+
+/// The color space the variance of a block is measured in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// The channels of the image as they are stored
+    Srgb,
+
+    /// CIELAB, where the same distance looks about as different anywhere, so
+    /// dark areas aren't split more than the eye can tell apart. Every pixel
+    /// is converted and a second summed-area table is built, which takes
+    /// about twice the time and memory before the compression starts
+    Lab,
+
+    /// The Rec. 709 luminance of the channels alone, so that areas of the
+    /// same brightness aren't split however colorful they are. This needs a
+    /// second summed-area table too, a quarter of the size of the first one
+    Luma
+}
+
+/// Which color a block is painted with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    /// The mean of every channel, which gives the least squared error
+    Mean,
+
+    /// The median of every channel, which keeps a few pixels on the other
+    /// side of an edge from tinting the whole block. This is slower, since the
+    /// pixels of every new block are read again
+    Median
+}
+
+/// Paints the blocks of a [`Compressor`] when it is rendered, see
+/// [`CompressorOptions::fill_strategy`]
+pub trait FillStrategy: std::fmt::Debug + Send + Sync {
+    /// Paints the block at `bound` over `canvas`, given its color
+    ///
+    /// The pixels of the block still hold the original ones when it is called,
+    /// so they can be read to fit the paint to them. `color` has a sample for
+    /// every channel of the image, in its depth.
+    fn fill(&self, canvas: &mut Canvas, bound: &Bound, color: &[u64; 4]);
+}
+
+/// The pixels a [`FillStrategy`] paints over, laid out like the image being
+/// compressed
+pub struct Canvas<'a> {
+    width: usize,
+    channels: usize,
+    depth: BitDepth,
+    data: &'a mut [u8]
+}
+
+impl Canvas<'_> {
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn depth(&self) -> BitDepth {
+        self.depth
+    }
+
+    fn pixel_len(&self) -> usize {
+        self.channels * self.depth.bytes()
+    }
+
+    /// The samples of the pixel at (`x`, `y`), the ones past the channels of
+    /// the image being 0
+    pub fn pixel(&self, x: usize, y: usize) -> [u64; 4] {
+        let pixel_len = self.pixel_len();
+        let pixel = &self.data[(y * self.width + x) * pixel_len..][..pixel_len];
+
+        let mut samples = [0; 4];
+        for (value, sample) in samples.iter_mut().zip(pixel.chunks(self.depth.bytes())) {
+            *value = read_sample(sample);
+        }
+
+        samples
+    }
+
+    /// Sets the samples of the pixel at (`x`, `y`), the ones past the channels
+    /// of the image are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, samples: &[u64; 4]) {
+        let (pixel_len, size) = (self.pixel_len(), self.depth.bytes());
+        let pixel = &mut self.data[(y * self.width + x) * pixel_len..][..pixel_len];
+        for (sample, &value) in pixel.chunks_mut(size).zip(samples) {
+            write_sample(value, sample);
+        }
+    }
+
+    /// Sets every pixel of `bound` to the same samples, which is faster than
+    /// setting them one by one
+    pub fn fill(&mut self, bound: &Bound, samples: &[u64; 4]) {
+        let (pixel_len, size) = (self.pixel_len(), self.depth.bytes());
+        let mut pixel = vec![0; pixel_len];
+        for (sample, &value) in pixel.chunks_mut(size).zip(samples) {
+            write_sample(value, sample);
+        }
+
+        for i in bound.y_min..bound.y_max {
+            let row = pixel_len * (i * self.width);
+            for j in bound.x_min..bound.x_max {
+                let idx = row + pixel_len * j;
+                self.data[idx..idx + pixel_len].copy_from_slice(&pixel);
+            }
+        }
+    }
+}
+
+/// Fills every block with its color, which is its mean unless another
+/// [`Aggregate`] is chosen. This is the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeanFill;
+
+impl FillStrategy for MeanFill {
+    fn fill(&self, canvas: &mut Canvas, bound: &Bound, color: &[u64; 4]) {
+        canvas.fill(bound, color);
+    }
+}
+
+/// Paints a bilinear ramp through the color of every block at its center,
+/// whose slopes are fitted to its pixels by least squares
+///
+/// Smooth content then needs fewer blocks for the same error, but the flat
+/// areas that make the output compress well become gradients.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BilinearFill;
+
+impl FillStrategy for BilinearFill {
+    fn fill(&self, canvas: &mut Canvas, bound: &Bound, color: &[u64; 4]) {
+        let slopes = bilinear_slopes(canvas, bound);
+        let center_x = (bound.x_min + bound.x_max - 1) as f64 / 2.0;
+        let center_y = (bound.y_min + bound.y_max - 1) as f64 / 2.0;
+        let max = canvas.depth.max_value() as f64;
+
+        for i in bound.y_min..bound.y_max {
+            for j in bound.x_min..bound.x_max {
+                let (x, y) = (j as f64 - center_x, i as f64 - center_y);
+                let samples = std::array::from_fn(|k| {
+                    let [dx, dy, dxy] = slopes[k];
+                    let value = color[k] as f64 + dx * x + dy * y + dxy * x * y;
+                    value.round().clamp(0.0, max) as u64
+                });
+
+                canvas.set_pixel(j, i, &samples);
+            }
+        }
+    }
+}
+
+// The slopes of the bilinear ramp that best fits every channel of the pixels
+// of `bound`, along x, y and their product. The coordinates are centered on
+// the block, which makes the terms of the ramp orthogonal: every slope is
+// fitted on its own, and none of them changes the mean. A block one pixel wide
+// or tall has no slope along that axis
+fn bilinear_slopes(canvas: &Canvas, bound: &Bound) -> [[f64; 3]; 4] {
+    let center_x = (bound.x_min + bound.x_max - 1) as f64 / 2.0;
+    let center_y = (bound.y_min + bound.y_max - 1) as f64 / 2.0;
+
+    let mut moments = [[0.0; 3]; 4];
+    for i in bound.y_min..bound.y_max {
+        let y = i as f64 - center_y;
+        for j in bound.x_min..bound.x_max {
+            let x = j as f64 - center_x;
+            for (moment, value) in moments.iter_mut().zip(canvas.pixel(j, i)) {
+                let value = value as f64;
+                moment[0] += value * x;
+                moment[1] += value * y;
+                moment[2] += value * x * y;
+            }
+        }
+    }
+
+    // The sums of the squared coordinates along every axis, n(n^2 - 1) / 12
+    let (w, h) = (bound.width() as f64, bound.height() as f64);
+    let (xx, yy) = (w * (w * w - 1.0) / 12.0, h * (h * h - 1.0) / 12.0);
+    let slope = |moment: f64, norm: f64| if norm > 0.0 { moment / norm } else { 0.0 };
+    moments.map(|[x, y, xy]| [slope(x, xx * h), slope(y, yy * w), slope(xy, xx * yy)])
+}
+
+// The median of every channel over the pixels of `bound`, the lower one when
+// there is an even number of pixels. Selecting it takes linear time, unlike
+// sorting the samples
+fn median(img: &Image, bound: &Bound) -> [u64; 4] {
+    let size = img.depth.bytes();
+    let pixel_len = img.channels * size;
+
+    let mut samples = vec![Vec::with_capacity(bound.area()); img.channels];
+    for i in bound.y_min..bound.y_max {
+        let row = i * img.width;
+        let pixels = &img.data[(row + bound.x_min) * pixel_len..(row + bound.x_max) * pixel_len];
+        for pixel in pixels.chunks(pixel_len) {
+            for (channel, sample) in samples.iter_mut().zip(pixel.chunks(size)) {
+                channel.push(read_sample(sample));
+            }
+        }
+    }
+
+    let mut median = [0; 4];
+    for (m, channel) in median.iter_mut().zip(&mut samples) {
+        let mid = (channel.len() - 1) / 2;
+        *m = *channel.select_nth_unstable(mid).1;
+    }
+
+    median
+}
+
+/// How a block is divided when it is split by a [`StandardSplit`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitMode {
+    /// Halve the block along the axis that leaves the least variance
+    Binary,
+
+    /// Divide the block into four quadrants
+    Quad
+}
+
+/// Where a block is cut when it is split by a [`StandardSplit`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CutPosition {
+    /// Cut the block in the middle
+    Midpoint,
+
+    /// Cut the block where the parts have the least variance, this is slower
+    /// but needs fewer blocks for the same tolerance
+    OptimalCut
+}
+
+/// Decides where the blocks of a [`Compressor`] are cut, see
+/// [`CompressorOptions::split_strategy`]
+///
+/// Blocks too small to be cut along either axis are set aside without asking
+/// the strategy.
+pub trait SplitStrategy: std::fmt::Debug + Send + Sync {
+    /// Where to cut the block at `bound`, or `None` to set it aside for good
+    ///
+    /// The cut has to be within [`SplitContext::cuts_x`] and
+    /// [`SplitContext::cuts_y`], otherwise it is ignored and the block is set
+    /// aside as if it couldn't be split.
+    fn split(&self, ctx: &SplitContext, bound: &Bound) -> Option<Cut>;
+}
+
+/// The strategy a [`Compressor`] splits blocks with by default, which cuts
+/// blocks along one or both axes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StandardSplit {
+    pub mode: SplitMode,
+    pub position: CutPosition
+}
+
+impl Default for StandardSplit {
+    fn default() -> Self {
+        Self { mode: SplitMode::Binary, position: CutPosition::Midpoint }
+    }
+}
+
+impl SplitStrategy for StandardSplit {
+    fn split(&self, ctx: &SplitContext, bound: &Bound) -> Option<Cut> {
+        let Bound { x_min, x_max, y_min, y_max } = *bound;
+        let halves_x = |x| [Bound::new(x_min, x, y_min, y_max), Bound::new(x, x_max, y_min, y_max)];
+        let halves_y = |y| [Bound::new(x_min, x_max, y_min, y), Bound::new(x_min, x_max, y, y_max)];
+
+        let (split_x, split_y) = match self.position {
+            // The midpoint is rounded down, so the first half is never larger
+            // than the second one
+            CutPosition::Midpoint => (
+                ctx.cuts_x(bound).map(|_| (x_min + x_max) / 2),
+                ctx.cuts_y(bound).map(|_| (y_min + y_max) / 2)
+            ),
+            CutPosition::OptimalCut => (
+                ctx.cuts_x(bound).and_then(|cuts| best_cut(cuts, |x| halves_x(x).iter().map(|half| ctx.mean_variance(half)).sum())),
+                ctx.cuts_y(bound).and_then(|cuts| best_cut(cuts, |y| halves_y(y).iter().map(|half| ctx.mean_variance(half)).sum()))
+            )
+        };
+
+        match (split_x, split_y) {
+            (Some(split_x), Some(split_y)) if self.mode == SplitMode::Quad => Some(Cut::Both(split_x, split_y)),
+            (Some(split_x), Some(split_y)) => {
+                let variance = |halves: [Bound; 2]| halves.iter().map(|half| ctx.variance(half)).sum::<u64>();
+                match variance(halves_x(split_x)) < variance(halves_y(split_y)) {
+                    true => Some(Cut::X(split_x)),
+                    false => Some(Cut::Y(split_y))
+                }
+            },
+            (Some(split_x), None) => Some(Cut::X(split_x)),
+            (None, Some(split_y)) => Some(Cut::Y(split_y)),
+            (None, None) => None
+        }
+    }
+}
+
+// Finds the cut among `cuts` that leaves the least variance on both sides,
+// `variance` being the summed variance of both parts when cutting at the given
+// position
+fn best_cut(cuts: RangeInclusive<usize>, variance: impl Fn(usize) -> u64) -> Option<usize> {
+    let mid = (cuts.start() + cuts.end()) / 2;
+    let mut best: Option<(usize, u64)> = None;
+    for cut in cuts {
+        let var = variance(cut);
+
+        // Prefer cutting near the middle when the variance is the same, so
+        // flat blocks are halved rather than split into thin slivers
+        let better = match best {
+            Some((best_cut, best_var)) => var < best_var || (
+                var == best_var && cut.abs_diff(mid) < best_cut.abs_diff(mid)
+            ),
+            None => true
+        };
+
+        if better {
+            best = Some((cut, var));
+        }
+    }
+
+    best.map(|(cut, _)| cut)
+}
+
+/// What a [`SplitStrategy`] knows about the block it splits
+pub struct SplitContext<'a> {
+    compressor: &'a Compressor,
+    depth: usize,
+
+    // The blocks measured by `variance`, which are kept if the cut makes them
+    measured: RefCell<Vec<HeapItem>>
+}
+
+impl SplitContext<'_> {
+    /// The image being compressed
+    pub fn image(&self) -> &Image {
+        &self.compressor.img
+    }
+
+    /// The columns the block at `bound` can be cut at, which leave both parts
+    /// at least as wide as the minimum block size, or `None` if it is too
+    /// narrow to be cut
+    pub fn cuts_x(&self, bound: &Bound) -> Option<RangeInclusive<usize>> {
+        self.cuts(bound.x_min, bound.x_max)
+    }
+
+    /// The rows the block at `bound` can be cut at, see
+    /// [`SplitContext::cuts_x`]
+    pub fn cuts_y(&self, bound: &Bound) -> Option<RangeInclusive<usize>> {
+        self.cuts(bound.y_min, bound.y_max)
+    }
+
+    fn cuts(&self, min: usize, max: usize) -> Option<RangeInclusive<usize>> {
+        let min_size = self.compressor.min_block_size;
+        (max - min >= 2 * min_size).then(|| min + min_size..=max - min_size)
+    }
+
+    fn allows(&self, bound: &Bound, cut: Cut) -> bool {
+        let within = |cuts: Option<RangeInclusive<usize>>, at| cuts.is_some_and(|cuts| cuts.contains(&at));
+        match cut {
+            Cut::X(x) => within(self.cuts_x(bound), x),
+            Cut::Y(y) => within(self.cuts_y(bound), y),
+            Cut::Both(x, y) => within(self.cuts_x(bound), x) && within(self.cuts_y(bound), y)
+        }
+    }
+
+    /// The summed squared deviation of the pixels of `bound` from the color
+    /// the block would be painted with, as the [`Compressor`] measures it
+    ///
+    /// The variances of several blocks can be added up and compared. This
+    /// reads the pixels of the block with [`Aggregate::Median`], but the
+    /// blocks measured here are reused if the cut makes them.
+    pub fn variance(&self, bound: &Bound) -> u64 {
+        let mut measured = self.measured.borrow_mut();
+        if let Some(item) = measured.iter().find(|item| item.bound == *bound) {
+            return item.var;
+        }
+
+        let item = self.compressor.new_item(*bound, self.depth);
+        let var = item.var;
+        measured.push(item);
+        var
+    }
+
+    /// Like [`SplitContext::variance`], around the mean of the block instead,
+    /// which takes a constant time whatever the size of the block
+    pub fn mean_variance(&self, bound: &Bound) -> u64 {
+        self.compressor.table_variance(bound)
+    }
+}
+
+/// Why [`CompressorOptions::build`] rejected the options
+#[derive(Debug)]
+pub enum OptionsError {
+    /// A channel weight is negative or not finite
+    ChannelWeight(f64),
+
+    /// The target PSNR is not finite
+    TargetPsnr(f64)
+}
+
+impl std::fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ChannelWeight(weight) => write!(f, "invalid channel weight {weight}, weights must be positive or 0"),
+            Self::TargetPsnr(psnr) => write!(f, "invalid target PSNR {psnr}, it must be finite")
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+/// The settings of a [`Compressor`]
+///
+/// Every setting has a default, so that `CompressorOptions::default()` gives
+/// the compression of the command line tool without any option. The tolerance
+/// and the maximum number of blocks are where [`compress_image`] stops, a
+/// [`Compressor`] is told where to stop by every call to
+/// [`Compressor::compress`] instead.
+#[derive(Clone, Debug)]
+pub struct CompressorOptions {
+    tolerance: u64,
+    max_blocks: Option<usize>,
+    min_block_size: usize,
+    max_depth: Option<usize>,
+    split_strategy: Arc<dyn SplitStrategy>,
+    variance_mode: VarianceMode,
+    metric: Metric,
+    aggregate: Aggregate,
+    fill_strategy: Arc<dyn FillStrategy>,
+    linear_light: bool,
+    alpha_weighting: bool,
+    channel_weights: [f64; 4],
+    target_psnr: Option<f64>,
+    regions: Vec<Region>,
+    seed: u64
+}
+
+impl Default for CompressorOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 8,
+            max_blocks: None,
+            min_block_size: 1,
+            max_depth: None,
+            split_strategy: Arc::new(StandardSplit::default()),
+            variance_mode: VarianceMode::Mean,
+            metric: Metric::Srgb,
+            aggregate: Aggregate::Mean,
+            fill_strategy: Arc::new(MeanFill),
+            linear_light: false,
+            alpha_weighting: false,
+            channel_weights: [1.0; 4],
+            target_psnr: None,
+            regions: Vec::new(),
+            seed: 0
+        }
+    }
+}
+
+impl CompressorOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks that the options make sense together
+    pub fn build(self) -> Result<Self, OptionsError> {
+        if let Some(&weight) = self.channel_weights.iter().find(|w| !(w.is_finite() && **w >= 0.0)) {
+            return Err(OptionsError::ChannelWeight(weight));
+        }
+
+        match self.target_psnr {
+            Some(psnr) if !psnr.is_finite() => Err(OptionsError::TargetPsnr(psnr)),
+            _ => Ok(self)
+        }
+    }
+
+    /// Split blocks until none of them has a variance above `tolerance`, see
+    /// [`Compressor::compress`]. It is 8 by default
+    pub fn tolerance(mut self, tolerance: u64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Stop splitting once there are at least `blocks` blocks
+    pub fn max_blocks(mut self, blocks: Option<usize>) -> Self {
+        self.max_blocks = blocks;
+        self
+    }
+
+    /// Never split blocks into parts narrower or shorter than `size` pixels
+    ///
+    /// In particular, blocks that are at most `size` pixels on both axes are
+    /// never split.
+    pub fn min_block(mut self, size: usize) -> Self {
+        self.min_block_size = size.max(1);
+        self
+    }
+
+    /// Never split a block more than `depth` times, the whole image being at
+    /// depth 0
+    ///
+    /// Blocks at the maximum depth are still painted with their average color,
+    /// whatever their variance.
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Choose where blocks are cut, see [`SplitStrategy`]. Blocks are halved
+    /// along the axis that leaves the least variance by default, see
+    /// [`StandardSplit`]
+    pub fn split_strategy(mut self, strategy: impl SplitStrategy + 'static) -> Self {
+        self.split_strategy = Arc::new(strategy);
+        self
+    }
+
+    /// Choose how the variance of blocks is measured, see [`VarianceMode`]
+    pub fn variance_mode(mut self, mode: VarianceMode) -> Self {
+        self.variance_mode = mode;
+        self
+    }
+
+    /// Choose the color space the variance of blocks is measured in, see
+    /// [`Metric`]
+    ///
+    /// With [`Metric::Lab`], the tolerance is measured in squared CIELAB
+    /// units, where a distance of about 2.3 is just noticeable. With
+    /// [`Metric::Luma`], it is measured on the luminance from 0 to 255. The
+    /// blocks are still painted with a color computed from the channels of
+    /// the image.
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Choose which color blocks are painted with, see [`Aggregate`]
+    ///
+    /// The variance of a block is measured around that color, so the
+    /// tolerance bounds the error of the reconstructed image either way.
+    pub fn aggregate(mut self, aggregate: Aggregate) -> Self {
+        self.aggregate = aggregate;
+        self
+    }
+
+    /// Choose how the pixels of blocks are painted, see [`FillStrategy`].
+    /// Blocks are filled with their color by default, see [`MeanFill`]
+    ///
+    /// This only changes the reconstructed image, the blocks are split the
+    /// same way. The error, the PSNR and the SSIM of the [`Compressor`] are
+    /// still those of flat blocks, and SVG output is always flat.
+    pub fn fill_strategy(mut self, strategy: impl FillStrategy + 'static) -> Self {
+        self.fill_strategy = Arc::new(strategy);
+        self
+    }
+
+    /// Average the colors of the blocks in linear light rather than in sRGB
+    ///
+    /// sRGB is gamma-encoded, so averaging it directly gives edges and
+    /// gradients a darker and duller color than the eye would blend them to.
+    /// This only changes the mean, see [`Aggregate`], and takes another
+    /// summed-area table as large as the one of the image.
+    pub fn linear_light(mut self, linear: bool) -> Self {
+        self.linear_light = linear;
+        self
+    }
+
+    /// Weight every pixel by its alpha, both in the color of the blocks and in
+    /// their variance
+    ///
+    /// Transparent pixels then don't count, so a block that is transparent
+    /// throughout has no variance whatever the colors hidden behind the
+    /// alpha, and semi-transparent edges don't tint the blocks they are in.
+    /// The median isn't weighted. This has no effect on images without alpha.
+    pub fn alpha_weighting(mut self, weighted: bool) -> Self {
+        self.alpha_weighting = weighted;
+        self
+    }
+
+    /// Multiply the squared deviations of the red, green, blue and alpha
+    /// channels by `weights` in the variance of blocks
+    ///
+    /// The gray channel is weighted by the mean of the red, green and blue
+    /// weights, and with [`Metric::Lab`] these weigh `L`, `a` and `b`
+    /// instead. The weights have no effect with [`Metric::Luma`], which
+    /// already weighs the channels. They are all 1 by default.
+    pub fn channel_weights(mut self, weights: [f64; 4]) -> Self {
+        self.channel_weights = weights;
+        self
+    }
+
+    /// Stop splitting once the peak signal-to-noise ratio of the reconstructed
+    /// image reaches `psnr` decibels, see [`Compressor::psnr`]
+    ///
+    /// This is checked along with the tolerance, so the tolerance should be 0
+    /// for the PSNR alone to decide when to stop.
+    pub fn target_psnr(mut self, psnr: Option<f64>) -> Self {
+        self.target_psnr = psnr;
+        self
+    }
+
+    /// Split the blocks that overlap one of `regions` until they are within
+    /// its tolerance, rather than the one given to [`Compressor::compress`]
+    ///
+    /// The lowest tolerance applies where regions overlap, and regions with a
+    /// higher tolerance than the one given to [`Compressor::compress`] have no
+    /// effect.
+    pub fn regions(mut self, regions: Vec<Region>) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Seed the random number generator of the compressor, see
+    /// [`Compressor::rng`], so that the same seed, image and settings always
+    /// give the same result. The seed is 0 by default
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+// Maps the weights of the red, green, blue and alpha channels to the channels
+// of `color_type`, or `None` if they all have a weight of 1
+fn channel_weights(color_type: ColorType, weights: [f64; 4]) -> Option<[f64; 4]> {
+    let [r, g, b, a] = weights;
+    let mapped = match color_type {
+        ColorType::Gray => [(r + g + b) / 3.0, 0.0, 0.0, 0.0],
+        ColorType::GrayAlpha => [(r + g + b) / 3.0, a, 0.0, 0.0],
+        ColorType::Rgb => [r, g, b, 0.0],
+        ColorType::Rgba => [r, g, b, a]
+    };
+
+    let unweighted = mapped[..color_type.channels()].iter().all(|&w| w == 1.0);
+    (!unweighted).then_some(mapped)
+}
+
+/// Why [`Compressor::resume`] couldn't restore a saved state
+#[derive(Debug)]
+pub enum StateError {
+    /// The state couldn't be read
+    Io(std::io::Error),
+
+    /// The data is not a state saved by [`Compressor::save_state`], or it is
+    /// corrupted
+    Format,
+
+    /// The state was saved while compressing another image
+    Image,
+
+    /// The state was saved with options that measure or paint the blocks
+    /// differently
+    Options
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Format => write!(f, "not a valid compression state"),
+            Self::Image => write!(f, "the state was saved from another image"),
+            Self::Options => write!(f, "the state was saved with different options")
+        }
+    }
+}
+
+impl std::error::Error for StateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for StateError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+// The first bytes of a saved state, with the version of its layout
+const STATE_MAGIC: &[u8; 8] = b"PNGPART\x01";
+
+// 64-bit FNV-1a, which is enough to tell whether a state belongs to an image
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// The values of a saved state are LEB128 varints: 7 bits per byte from the
+// lowest, with the top bit set on every byte but the last. Coordinates and
+// colors mostly take 1 to 3 bytes instead of 8
+fn write_varint(w: &mut impl std::io::Write, mut value: u64) -> std::io::Result<()> {
+    let mut bytes = [0; 10];
+    let mut len = 0;
+    loop {
+        bytes[len] = (value & 0x7f) as u8;
+        value >>= 7;
+        len += 1;
+        if value == 0 {
+            break;
+        }
+        bytes[len - 1] |= 0x80;
+    }
+
+    w.write_all(&bytes[..len])
+}
+
+fn read_varint(r: &mut impl Read) -> Result<u64, StateError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        r.read_exact(&mut byte).map_err(|err| match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => StateError::Format,
+            _ => StateError::Io(err)
+        })?;
+
+        let bits = (byte[0] & 0x7f) as u64;
+        if bits << shift >> shift != bits {
+            return Err(StateError::Format);
+        }
+
+        value |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(StateError::Format)
+}
+
+/// Partitions an image into blocks, splitting the block with the largest
+/// variance first
+pub struct Compressor {
+    img: Image,
+    table: SummedAreaTable,
+
+    // The color space the variance is measured in, and the summed-area table
+    // of the image in it unless that is sRGB
+    metric: Metric,
+    metric_table: Option<SummedAreaTable>,
+
+    // The summed-area table of the image in linear light, if the colors are
+    // averaged in it
+    linear: Option<SummedAreaTable>,
+
+    // The weight of every channel of the image in the variance, or `None` if
+    // they all have a weight of 1
+    channel_weights: Option<[f64; 4]>,
+
+    // Whether the pixels count as much as they are opaque, in which case the
+    // colors of the tables above are premultiplied by the alpha
+    alpha_weighted: bool,
+
+    // The summed-area table of the image premultiplied by its alpha, if it
+    // has an alpha channel and the pixels are weighted by it
+    premultiplied: Option<SummedAreaTable>,
+    min_block_size: usize,
+    max_depth: usize,
+    split_strategy: Arc<dyn SplitStrategy>,
+    variance_mode: VarianceMode,
+    aggregate: Aggregate,
+    fill_strategy: Arc<dyn FillStrategy>,
+    target_psnr: Option<f64>,
+    regions: Vec<Region>,
+    seed: u64,
+
+    heap: BinaryHeap<HeapItem>,
+
+    // Blocks that can't be split any further, they are kept out of the heap
+    // so they never block the stopping condition
+    done: Vec<HeapItem>,
+    depth_limited: usize,
+    splits: usize,
+
+    // The summed error of every block, which is the squared error of the
+    // reconstructed image. It is updated on every split so the PSNR is known
+    // at any time
+    error: u128
+}
+
+impl Compressor {
+    /// Creates a compressor with the whole image as a single block, or no
+    /// block at all if the image is empty
+    pub fn new(img: Image, options: CompressorOptions) -> Self {
+        let table = SummedAreaTable::new(&img);
+        let root = Bound::new(0, img.width, 0, img.height);
+        let channel_weights = channel_weights(img.color_type(), options.channel_weights);
+
+        let mut compressor = Self {
+            img,
+            table,
+            metric: options.metric,
+            metric_table: None,
+            linear: None,
+            channel_weights,
+            alpha_weighted: options.alpha_weighting,
+            premultiplied: None,
+            min_block_size: options.min_block_size.max(1),
+            max_depth: options.max_depth.unwrap_or(usize::MAX),
+            split_strategy: options.split_strategy,
+            variance_mode: options.variance_mode,
+            aggregate: options.aggregate,
+            fill_strategy: options.fill_strategy,
+            target_psnr: options.target_psnr,
+            regions: options.regions,
+            seed: options.seed,
+            heap: BinaryHeap::new(),
+            done: Vec::new(),
+            depth_limited: 0,
+            splits: 0,
+            error: 0
+        };
+
+        // The other tables are built from the colors with or without alpha
+        let premultiply = compressor.premultiplies();
+        if premultiply {
+            compressor.premultiplied = Some(SummedAreaTable::new(&premultiplied_image(&compressor.img)));
+        }
+
+        compressor.metric_table = compressor.metric_image().map(|img| SummedAreaTable::new(&img));
+        if options.linear_light {
+            compressor.linear = Some(SummedAreaTable::new(&linear_image(&compressor.img, premultiply)));
+        }
+
+        // An empty block has no mean, there is nothing to compress
+        if root.area() > 0 {
+            let item = compressor.new_item(root, 0);
+            compressor.error = item.error as u128;
+            compressor.heap.push(item);
+        }
+
+        compressor
+    }
+
+    // How much larger a squared deviation is in the table the variance is
+    // measured with than between 8-bit channels
+    fn variance_scale(&self) -> u64 {
+        match self.metric {
+            Metric::Srgb => self.img.depth.variance_scale(),
+            Metric::Lab => LAB_SCALE * LAB_SCALE,
+            Metric::Luma => BitDepth::Sixteen.variance_scale()
+        }
+    }
+
+    // The number of channels in the table the variance is measured with
+    fn variance_channels(&self) -> usize {
+        match self.metric {
+            Metric::Luma => 1,
+            _ => self.img.channels
+        }
+    }
+
+    fn variance_table(&self) -> &SummedAreaTable {
+        self.metric_table.as_ref().or(self.premultiplied.as_ref()).unwrap_or(&self.table)
+    }
+
+    // The image in the color space of the metric, unless that is sRGB
+    fn metric_image(&self) -> Option<Image> {
+        match self.metric {
+            Metric::Srgb => None,
+            Metric::Lab => Some(lab_image(&self.img, self.premultiplies())),
+            Metric::Luma => Some(luma_image(&self.img, self.premultiplies()))
+        }
+    }
+
+    // The variance of a block around its mean, in the table the variance is
+    // measured with
+    fn table_variance(&self, bound: &Bound) -> u64 {
+        let count = bound.area() as u64;
+        let sums = self.variance_table().sums(bound);
+        self.weighted_variance(&sums, &sums.mean(count), count)
+    }
+
+    fn weighted_variance(&self, sums: &Sums, mean: &[u64; 4], count: u64) -> u64 {
+        match &self.channel_weights {
+            Some(weights) if self.metric != Metric::Luma => sums.weighted_variance(mean, count, weights),
+            _ => sums.variance(mean, count)
+        }
+    }
+
+    // Whether the colors of the tables other than `table` are premultiplied
+    fn premultiplies(&self) -> bool {
+        self.alpha_weighted && self.img.color_type().has_alpha()
+    }
+
+    // The mean of a block in one of the tables, whose channels go up to `max`.
+    // It is weighted by the alpha if the colors of the table are premultiplied
+    fn table_mean(&self, table: &SummedAreaTable, bound: &Bound, max: u64) -> [u64; 4] {
+        let count = bound.area() as u64;
+        let sums = table.sums(bound);
+        if self.premultiplies() {
+            sums.unpremultiplied_mean(count, self.img.channels, max)
+        } else {
+            sums.mean(count)
+        }
+    }
+
+    fn new_item(&self, bound: Bound, depth: usize) -> HeapItem {
+        let count = bound.area() as u64;
+        let sums = self.table.sums(&bound);
+        let color = match (self.aggregate, &self.linear) {
+            (Aggregate::Mean, Some(linear)) => self.encode_linear(self.table_mean(linear, &bound, 65535)),
+            (Aggregate::Mean, None) => {
+                let table = self.premultiplied.as_ref().unwrap_or(&self.table);
+                self.table_mean(table, &bound, self.img.depth.max_value())
+            },
+
+            // The transfer function keeps the order of the values, so the
+            // median is the same in linear light
+            (Aggregate::Median, _) => median(&self.img, &bound)
+        };
+
+        // Perceptual and alpha-weighted distances are measured around the
+        // mean of their own table, whatever color the block is painted with
+        let error = sums.variance(&color, count);
+        let var = if self.metric_table.is_some() || self.premultiplied.is_some() {
+            self.table_variance(&bound)
+        } else {
+            self.weighted_variance(&sums, &color, count)
+        };
+
+        let weight = self.variance_mode.weight(&bound, self.variance_channels(), self.variance_scale());
+        HeapItem { var, error, color, depth, weight, bound }
+    }
+
+    // Encodes a color of the linear image back to the depth of the image
+    fn encode_linear(&self, color: [u64; 4]) -> [u64; 4] {
+        let color_len = if self.img.color_type().is_gray() { 1 } else { 3 };
+        let max = self.img.depth.max_value();
+
+        let mut encoded = [0; 4];
+        for (k, (c, &value)) in encoded.iter_mut().zip(&color).take(self.img.channels).enumerate() {
+            *c = if k < color_len {
+                (linear_to_srgb(value as f32 / 65535.0) * max as f32).round() as u64
+            } else {
+                (value * max + 32767) / 65535
+            };
+        }
+
+        encoded
+    }
+
+    // The tolerance a block is split down to, which is the lowest among
+    // `tolerance` and the regions it overlaps
+    fn tolerance_of(&self, item: &HeapItem, tolerance: u64) -> u64 {
+        self.regions.iter()
+            .filter(|region| region.bound.intersects(&item.bound))
+            .map(|region| region.tolerance)
+            .fold(tolerance, u64::min)
+    }
+
+    /// The seed every random number of the compression derives from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// A new random number generator seeded with [`Compressor::seed`], for the
+    /// randomized steps applied to the compressed image, such as
+    /// [`Image::dither`]. Every call starts the same sequence over
+    pub fn rng(&self) -> SplitMix64 {
+        SplitMix64::new(self.seed)
+    }
+
+    /// The number of blocks the image is currently partitioned into
+    pub fn block_count(&self) -> usize {
+        self.heap.len() + self.done.len()
+    }
+
+    /// The number of blocks that may still be split
+    pub fn splittable_count(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// The number of times a block was split
+    pub fn split_count(&self) -> usize {
+        self.splits
+    }
+
+    /// The largest variance among the blocks that can still be split
+    pub fn max_variance(&self) -> Option<u64> {
+        self.heap.peek().map(HeapItem::weighted_var)
+    }
+
+    /// The largest variance among all blocks, including the ones that can't
+    /// be split any further
+    pub fn max_block_variance(&self) -> u64 {
+        self.heap.iter().chain(self.done.iter()).map(HeapItem::weighted_var).max().unwrap_or(0)
+    }
+
+    /// The mean squared error of every channel of the reconstructed image
+    /// against the original, without reconstructing it
+    ///
+    /// Like the tolerance, it is measured on channels from 0 to 255 whatever
+    /// the depth of the image.
+    pub fn mean_squared_error(&self) -> f64 {
+        self.state().mean_squared_error()
+    }
+
+    /// The counts and errors that tell how far the compression went, see
+    /// [`Compressor::compress_until`]
+    ///
+    /// This takes no time, nothing is measured again.
+    pub fn state(&self) -> CompressorState {
+        // Every pixel is painted with the color of its block, so the error of
+        // a block is exactly its variance around that color
+        CompressorState {
+            blocks: self.block_count(),
+            splits: self.splits,
+            max_variance: self.max_variance().unwrap_or(0),
+            squared_error: self.error as f64 / self.img.depth.variance_scale() as f64,
+            samples: self.img.channels * self.img.width * self.img.height
+        }
+    }
+
+    /// The peak signal-to-noise ratio of the reconstructed image against the
+    /// original in decibels, without reconstructing it
+    ///
+    /// The higher, the closer the images are. It is infinite when they are
+    /// identical.
+    pub fn psnr(&self) -> f64 {
+        self.state().psnr()
+    }
+
+    /// Counts the blocks by how far they are from being split again
+    pub fn block_stats(&self, tolerance: u64) -> BlockStats {
+        let below_tolerance = self.heap.iter()
+            .filter(|item| item.within(self.tolerance_of(item, tolerance)))
+            .count();
+        BlockStats {
+            below_tolerance,
+            above_tolerance: self.heap.len() - below_tolerance,
+            limited_by_size: self.done.len() - self.depth_limited,
+            limited_by_depth: self.depth_limited
+        }
+    }
+
+    /// Splits blocks until none of them has a variance above `tolerance`, or
+    /// until there are at least `max_blocks` blocks
+    ///
+    /// Blocks that overlap a region given to [`CompressorOptions::regions`] are
+    /// split until they are within the tolerance of that region instead.
+    ///
+    /// The variance of a block comes from the squared differences between
+    /// every channel (from 0 to 255) of its pixels and its color, see
+    /// [`CompressorOptions::aggregate`]. They are averaged by default, so a
+    /// block whose pixels are all off by `d` on every channel has a variance
+    /// of `d^2`, whatever its size. With
+    /// [`VarianceMode::Total`] they are summed instead, giving a block of `n`
+    /// pixels and `c` channels a variance of `c * n * d^2`. The channels of
+    /// 16-bit images are measured in the same unit, as fractions of 255.
+    ///
+    /// Calling it again with a lower tolerance adds detail from where it
+    /// stopped. Blocks are only ever split, and whether a block is split
+    /// doesn't depend on the order of the splits, so compressing down to 256
+    /// and then to 128 gives the same blocks as compressing down to 128 at
+    /// once. This doesn't hold when the number of blocks or the PSNR stopped
+    /// the first call, since those depend on the order.
+    pub fn compress(&mut self, tolerance: u64, max_blocks: Option<usize>) -> StopReason {
+        self.compress_with(tolerance, max_blocks, |_| ControlFlow::Continue(()))
+    }
+
+    /// Like [`Compressor::compress`], stopping with [`StopReason::Cancelled`]
+    /// once `cancel` is set, from another thread for instance
+    ///
+    /// The flag is checked between splits, which costs next to nothing next
+    /// to the splits themselves. The blocks split so far are kept, so the
+    /// compressor can still be reconstructed at the detail it reached, or
+    /// compressed further.
+    pub fn compress_cancellable(&mut self, tolerance: u64, max_blocks: Option<usize>, cancel: &AtomicBool) -> StopReason {
+        if cancel.load(atomic::Ordering::Relaxed) {
+            return StopReason::Cancelled;
+        }
+
+        let reason = self.compress_with(tolerance, max_blocks, |_| match cancel.load(atomic::Ordering::Relaxed) {
+            true => ControlFlow::Break(()),
+            false => ControlFlow::Continue(())
+        });
+
+        match reason {
+            StopReason::Interrupted => StopReason::Cancelled,
+            reason => reason
+        }
+    }
+
+    /// Like [`Compressor::compress`], calling `on_split` with every split
+    ///
+    /// The callback is called before the new blocks are queued, so the events
+    /// come in the order the splits are made. Returning
+    /// [`ControlFlow::Break`] stops with [`StopReason::Interrupted`] once the
+    /// split it was told about is done.
+    pub fn compress_with(
+        &mut self,
+        tolerance: u64,
+        max_blocks: Option<usize>,
+        on_split: impl FnMut(&SplitEvent) -> ControlFlow<()>
+    ) -> StopReason {
+        // Every split adds at least one block, and a block that can't be split
+        // is moved out of the heap, so the loop ends even if `max_blocks`
+        // exceeds the pixel count
+        let max_blocks = max_blocks.unwrap_or(usize::MAX);
+        let target_psnr = self.target_psnr;
+        let until = |state: &CompressorState| {
+            if state.blocks >= max_blocks {
+                Some(StopReason::MaxBlocks)
+            } else if target_psnr.is_some_and(|psnr| state.psnr() >= psnr) {
+                Some(StopReason::TargetPsnr)
+            } else {
+                None
+            }
+        };
+
+        self.refine(tolerance, until, on_split)
+    }
+
+    /// Splits blocks until `stop` returns `true`, which is asked before every
+    /// split
+    ///
+    /// Returns [`StopReason::Interrupted`] once `stop` asks to, or
+    /// [`StopReason::Tolerance`] if the image becomes exact first. Neither a
+    /// tolerance nor [`CompressorOptions::target_psnr`] stops it, so any rule
+    /// can be built from the [`CompressorState`], like a PSNR target, a
+    /// budget of blocks or a deadline. The state is only a few counters, so
+    /// asking every split costs next to nothing.
+    pub fn compress_until(&mut self, mut stop: impl FnMut(&CompressorState) -> bool) -> StopReason {
+        self.refine(0, |state| stop(state).then_some(StopReason::Interrupted), |_| ControlFlow::Continue(()))
+    }
+
+    // Splits blocks until they are all within `tolerance`, or the tolerance of
+    // the regions they overlap. `until` can stop before any split with its own
+    // reason, and `on_split` after one
+    fn refine(
+        &mut self,
+        tolerance: u64,
+        mut until: impl FnMut(&CompressorState) -> Option<StopReason>,
+        mut on_split: impl FnMut(&SplitEvent) -> ControlFlow<()>
+    ) -> StopReason {
+        // Blocks within their own tolerance are set aside so the ones behind
+        // them can still be split inside the regions. They are put back before
+        // returning, so that a lower tolerance can be reached afterwards
+        let mut within = Vec::new();
+        let reason = loop {
+            match self.heap.peek() {
+                // Every other block has a lower variance, so they are all
+                // within the tolerance unless a region is stricter
+                Some(item) if self.regions.is_empty() && item.within(tolerance) => break StopReason::Tolerance,
+                Some(item) if item.within(self.tolerance_of(item, tolerance)) => {
+                    within.push(self.heap.pop().unwrap());
+                    continue;
+                },
+                Some(_) => {},
+                None if !within.is_empty() => break StopReason::Tolerance,
+                None => break StopReason::Exhausted
+            }
+
+            // The blocks set aside still count
+            let state = self.state();
+            if let Some(reason) = until(&CompressorState { blocks: state.blocks + within.len(), ..state }) {
+                break reason;
+            }
+
+            let item = self.heap.peek().unwrap();
+            let (variance, parent) = (item.weighted_var(), item.bound);
+            let iteration = self.splits;
+            let mut flow = ControlFlow::Continue(());
+            self.add_detail(|cut, children| {
+                let children = children.iter().map(|child| (child.bound, child.weighted_var())).collect();
+                flow = on_split(&SplitEvent { iteration, parent, variance, cut, children });
+            });
+
+            if flow.is_break() {
+                break StopReason::Interrupted;
+            }
+        };
+
+        self.heap.extend(within);
+        reason
+    }
+
+    /// Splits the block with the largest variance among the ones that can
+    /// still be split, and describes the split
+    ///
+    /// Blocks at the maximum depth or too small to be split are set aside on
+    /// the way. Returns `None` once no block with any variance left can be
+    /// split, so stepping until then splits the image as finely as the options
+    /// allow. This lets callers choose when to stop, [`Compressor::compress`]
+    /// splits the blocks in the same order.
+    pub fn step(&mut self) -> Option<StepInfo> {
+        loop {
+            let item = self.heap.peek().filter(|item| item.var > 0)?;
+            let (variance, bound) = (item.weighted_var(), item.bound);
+            if let Some(cut) = self.add_detail(|_, _| ()) {
+                return Some(StepInfo { variance, bound, cut, blocks: self.block_count() });
+            }
+        }
+    }
+
+    // Splits the block with the largest variance, or sets it aside if it can't
+    // be split. `before_push` sees the cut and the new blocks before they are
+    // queued
+    fn add_detail(&mut self, before_push: impl FnOnce(Cut, &[HeapItem])) -> Option<Cut> {
+        let item = self.heap.pop().unwrap();
+        if item.depth >= self.max_depth {
+            self.done.push(item);
+            self.depth_limited += 1;
+            return None;
+        }
+
+        let bound = &item.bound;
+        let depth = item.depth + 1;
+
+        let ctx = SplitContext { compressor: self, depth, measured: RefCell::new(Vec::new()) };
+        let cut = match (ctx.cuts_x(bound), ctx.cuts_y(bound)) {
+            (None, None) => None,
+            _ => self.split_strategy.split(&ctx, bound).filter(|&cut| ctx.allows(bound, cut))
+        };
+
+        let mut measured = ctx.measured.into_inner();
+        let Some(cut) = cut else {
+            self.done.push(item);
+            return None;
+        };
+
+        let children: Vec<HeapItem> = cut.split(bound).into_iter().map(|bound| {
+            match measured.iter().position(|item| item.bound == bound) {
+                Some(i) => measured.swap_remove(i),
+                None => self.new_item(bound, depth)
+            }
+        }).collect();
+
+        before_push(cut, &children);
+        self.error -= item.error as u128;
+        self.error += children.iter().map(|child| child.error as u128).sum::<u128>();
+        self.heap.extend(children);
+        self.splits += 1;
+        Some(cut)
+    }
+
+    /// Every block of the partition, sorted from top to bottom and then from
+    /// left to right. They cover every pixel of the image exactly once
+    pub fn blocks(&self) -> Vec<Block> {
+        let color_len = if self.img.color_type().is_gray() { 1 } else { 3 };
+        let max = self.img.depth.max_value();
+        let mut blocks: Vec<Block> = self.heap.iter().chain(self.done.iter()).map(|item| {
+            let (color, alpha) = item.color[..self.img.channels].split_at(color_len);
+            let alpha = alpha.first().copied().unwrap_or(max);
+            let color = match *color {
+                [l] => [l, l, l, alpha],
+                [r, g, b] => [r, g, b, alpha],
+                _ => unreachable!()
+            }.map(|c| (c * 255 / max) as u8);
+
+            Block { bound: item.bound, color, samples: item.color, variance: item.weighted_var() }
+        }).collect();
+
+        blocks.sort_unstable_by_key(|block| (block.bound.y_min, block.bound.x_min));
+        blocks
+    }
+
+    /// The blocks along with the size, channels and depth of the image, see
+    /// [`Partition`]
+    pub fn partition(&self) -> Partition {
+        Partition {
+            width: self.img.width,
+            height: self.img.height,
+            channels: self.img.channels,
+            depth: self.img.depth,
+            blocks: self.blocks()
+        }
+    }
+
+    // Identifies the options that the variances and colors of the blocks
+    // depend on, the others only change which blocks are split next
+    fn options_fingerprint(&self) -> u64 {
+        let options = format!(
+            "{:?} {:?} {:?} {} {} {:?}",
+            self.metric, self.variance_mode, self.aggregate, self.linear.is_some(), self.alpha_weighted,
+            self.channel_weights
+        );
+        fnv1a(options.as_bytes())
+    }
+
+    /// Writes every block along with the progress of the compression, so that
+    /// it can go on later with [`Compressor::resume`]
+    ///
+    /// The layout is binary: a header with the size, a hash of the image and
+    /// of the options and the counters, then the bound, depth, variance, error
+    /// and color of every block, as variable-length integers.
+    pub fn save_state(&self, w: impl std::io::Write) -> std::io::Result<()> {
+        use std::io::Write as _;
+
+        let mut w = BufWriter::new(w);
+        w.write_all(STATE_MAGIC)?;
+
+        let header = [
+            self.img.width as u64,
+            self.img.height as u64,
+            self.img.channels as u64,
+            self.img.depth.bytes() as u64,
+            fnv1a(&self.img.data),
+            self.options_fingerprint(),
+            self.splits as u64,
+            self.depth_limited as u64,
+            self.heap.len() as u64,
+            self.done.len() as u64
+        ];
+
+        // The heap is written in its own order, which is already a valid
+        // heap, so it is restored as it was and ties are broken the same way
+        let items = self.heap.iter().chain(self.done.iter()).flat_map(|item| {
+            let Bound { x_min, x_max, y_min, y_max } = item.bound;
+            let [r, g, b, a] = item.color;
+            let [x_min, x_max, y_min, y_max, depth] = [x_min, x_max, y_min, y_max, item.depth].map(|value| value as u64);
+            [x_min, x_max, y_min, y_max, depth, item.var, item.error, r, g, b, a]
+        });
+
+        for value in header.into_iter().chain(items) {
+            write_varint(&mut w, value)?;
+        }
+
+        w.flush()
+    }
+
+    /// Creates a compressor that goes on from a state written by
+    /// [`Compressor::save_state`], ready for more calls to
+    /// [`Compressor::compress`]
+    ///
+    /// `img` must be the image the state was saved from, and `options` must
+    /// measure and paint the blocks the same way, otherwise an error tells
+    /// which one differs. Compressing to a tolerance, saving, resuming and
+    /// compressing to a lower one gives the same blocks as compressing to the
+    /// lower tolerance at once.
+    pub fn resume(img: Image, options: CompressorOptions, r: impl Read) -> Result<Self, StateError> {
+        let mut r = std::io::BufReader::new(r);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).map_err(|_| StateError::Format)?;
+        if &magic != STATE_MAGIC {
+            return Err(StateError::Format);
+        }
+
+        let mut header = [0; 10];
+        for value in &mut header {
+            *value = read_varint(&mut r)?;
+        }
+
+        let [width, height, channels, depth, hash, fingerprint, splits, depth_limited, heap_len, done_len] = header;
+        let size = [img.width, img.height, img.channels, img.depth.bytes()].map(|value| value as u64);
+        if [width, height, channels, depth] != size || hash != fnv1a(&img.data) {
+            return Err(StateError::Image);
+        }
+
+        let mut compressor = Self::new(img, options);
+        if fingerprint != compressor.options_fingerprint() {
+            return Err(StateError::Options);
+        }
+
+        // Every block covers at least a pixel, which bounds the counts before
+        // anything is allocated
+        let pixels = (compressor.img.width * compressor.img.height) as u64;
+        if heap_len.checked_add(done_len).is_none_or(|count| count > pixels) || depth_limited > done_len {
+            return Err(StateError::Format);
+        }
+
+        let mut read_item = || -> Result<HeapItem, StateError> {
+            let mut values = [0; 11];
+            for value in &mut values {
+                *value = read_varint(&mut r)?;
+            }
+
+            let [x_min, x_max, y_min, y_max, depth, var, error, r, g, b, a] = values;
+            let [x_min, x_max, y_min, y_max, depth] = [x_min, x_max, y_min, y_max, depth].map(|value| value as usize);
+            let (width, height) = (compressor.img.width, compressor.img.height);
+            if x_min >= x_max || y_min >= y_max || x_max > width || y_max > height {
+                return Err(StateError::Format);
+            }
+
+            let bound = Bound::new(x_min, x_max, y_min, y_max);
+            let (channels, scale) = (compressor.variance_channels(), compressor.variance_scale());
+            let weight = compressor.variance_mode.weight(&bound, channels, scale);
+            Ok(HeapItem { var, error, color: [r, g, b, a], depth, weight, bound })
+        };
+
+        let heap = (0..heap_len).map(|_| read_item()).collect::<Result<Vec<_>, _>>()?;
+        let done = (0..done_len).map(|_| read_item()).collect::<Result<Vec<_>, _>>()?;
+
+        compressor.error = heap.iter().chain(&done).map(|item| item.error as u128).sum();
+        compressor.heap = BinaryHeap::from(heap);
+        compressor.done = done;
+        compressor.splits = splits as usize;
+        compressor.depth_limited = depth_limited as usize;
+        Ok(compressor)
+    }
+
+    /// Renders every block as a rectangle of its average color in an SVG
+    /// document the size of the image
+    ///
+    /// SVG colors are 8-bit, see [`Block::color`]. Fully transparent
+    /// blocks are left out.
+    pub fn to_svg(&self) -> String {
+        let (w, h) = (self.img.width, self.img.height);
+
+        // Without crisp edges, anti-aliasing leaves seams between the blocks
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" \
+            shape-rendering=\"crispEdges\">\n"
+        );
+
+        for Block { bound, color: [r, g, b, alpha], .. } in self.blocks() {
+            if alpha == 0 {
+                continue;
+            }
+
+            let _ = write!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"rgb({r},{g},{b})\"",
+                bound.x_min, bound.y_min, bound.width(), bound.height()
+            );
+
+            if alpha < 255 {
+                let _ = write!(svg, " fill-opacity=\"{:.3}\"", alpha as f64 / 255.0);
+            }
+
+            svg.push_str("/>\n");
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Describes the partition as a JSON object with the `width` and `height`
+    /// of the image and its `blocks`
+    ///
+    /// Every block is an object with the position `x`, `y` and the size `w`,
+    /// `h` of its rectangle, and its color `r`, `g`, `b`, `a`, see
+    /// [`Compressor::blocks`]. Unlike in SVG, transparent blocks are kept.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        let blocks = self.blocks().iter().map(|Block { bound, color: [r, g, b, a], .. }| BlockRecord {
+            x: bound.x_min,
+            y: bound.y_min,
+            w: bound.width(),
+            h: bound.height(),
+            r: *r,
+            g: *g,
+            b: *b,
+            a: *a
+        }).collect();
+
+        let record = PartitionRecord { width: self.img.width, height: self.img.height, blocks };
+        let mut json = serde_json::to_string(&record).expect("records only hold numbers");
+        json.push('\n');
+        json
+    }
+
+    /// The structural similarity of the reconstructed image against the
+    /// original, without reconstructing it, see [`ssim`]
+    pub fn ssim(&self) -> f64 {
+        let color_len = if self.img.color_type().is_gray() { 1 } else { 3 };
+        let mut reconstructed = vec![0.0; self.img.width * self.img.height];
+        for item in self.heap.iter().chain(self.done.iter()) {
+            let luma = luma(&item.color[..color_len], self.img.depth);
+            for i in item.bound.y_min..item.bound.y_max {
+                let row = i * self.img.width;
+                reconstructed[row + item.bound.x_min..row + item.bound.x_max].fill(luma);
+            }
+        }
+
+        mean_ssim(self.img.width, self.img.height, &image_luma(&self.img), &reconstructed)
+    }
+
+    /// A grayscale image of how far every pixel is from the average color of
+    /// its block, which is the largest difference among its channels
+    ///
+    /// Bright pixels are the ones that lose the most detail when the image is
+    /// reconstructed. The error map has the depth of the image.
+    pub fn error_map(&self) -> Image {
+        let size = self.img.depth.bytes();
+        let pixel_len = self.img.channels * size;
+
+        let mut data = vec![0; self.img.width * self.img.height * size];
+        for item in self.heap.iter().chain(self.done.iter()) {
+            for i in item.bound.y_min..item.bound.y_max {
+                for j in item.bound.x_min..item.bound.x_max {
+                    let idx = i * self.img.width + j;
+                    let pixel = &self.img.data[idx * pixel_len..][..pixel_len];
+                    let diff = pixel.chunks(size).zip(&item.color)
+                        .map(|(sample, &m)| read_sample(sample).abs_diff(m))
+                        .max()
+                        .unwrap_or(0);
+
+                    write_sample(diff, &mut data[idx * size..][..size]);
+                }
+            }
+        }
+
+        Image {
+            width: self.img.width,
+            height: self.img.height,
+            channels: 1,
+            depth: self.img.depth,
+            data: data.into()
+        }
+    }
+
+    // Paints every block over `data`, which holds the pixels of the image.
+    // The blocks cover every pixel
+    fn paint(&self, data: &mut [u8]) {
+        let mut canvas = Canvas { width: self.img.width, channels: self.img.channels, depth: self.img.depth, data };
+        for item in self.heap.iter().chain(self.done.iter()) {
+            self.fill_strategy.fill(&mut canvas, &item.bound, &item.color);
+        }
+    }
+
+    /// Paints every block into a new image, so that the compression can go on
+    /// afterwards
+    pub fn render(&self) -> Image {
+        let mut data = self.img.data.to_vec();
+        self.paint(&mut data);
+
+        Image {
+            width: self.img.width,
+            height: self.img.height,
+            channels: self.img.channels,
+            depth: self.img.depth,
+            data: data.into()
+        }
+    }
+
+    /// The image being compressed
+    pub fn image(&self) -> &Image {
+        &self.img
+    }
+
+    /// Paints every block, reusing the memory of the original image
+    ///
+    /// The compressor is consumed, since its image is overwritten. Use
+    /// [`Compressor::render`] to compare the result with the original or to
+    /// go on compressing.
+    pub fn reconstruct(mut self) -> Image {
+        let mut data = std::mem::take(&mut self.img.data);
+        self.paint(&mut data);
+        self.img.data = data;
+        self.img
+    }
+}
+
+/// Partitions `img` with `options` until no block has a variance above their
+/// tolerance, or until there are as many blocks as they allow, and returns the
+/// flattened image
+pub fn compress_image(img: Image, options: &CompressorOptions) -> Image {
+    let (tolerance, max_blocks) = (options.tolerance, options.max_blocks);
+    let mut compressor = Compressor::new(img, options.clone());
+    compressor.compress(tolerance, max_blocks);
+    compressor.reconstruct()
+}
+
+/// Decodes the PNG in `input`, partitions it with `options` like
+/// [`compress_image`] and returns the PNG optimized with `optimize`, without
+/// touching the filesystem
+pub fn compress_png(
+    input: impl Read,
+    options: &CompressorOptions,
+    optimize: &OptimizeOptions
+) -> Result<Vec<u8>, PngpartError> {
+    let img = compress_image(decode_image(input)?, options);
+    optimize_png(&encode_png_fast(&img)?, optimize)
+}
+
+/// Compresses a PNG that is already in memory, see [`compress_png`]
+pub fn compress_bytes(
+    png: &[u8],
+    options: &CompressorOptions,
+    optimize: &OptimizeOptions
+) -> Result<Vec<u8>, PngpartError> {
+    compress_png(png, options, optimize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An 8-bit image whose samples change along both axes, so that it takes
+    // many blocks to be exact
+    fn gradient(width: usize, height: usize, channels: usize) -> Image {
+        let data = (0..height).flat_map(|y| (0..width).flat_map(move |x| {
+            (0..channels).map(move |k| ((x * 7 + y * 13 + k * 50) % 256) as u8)
+        })).collect();
+
+        Image { width, height, channels, depth: BitDepth::Eight, data }
+    }
+
+    // The names of the chunks of a PNG, in order
+    fn chunk_names(png: &[u8]) -> Vec<[u8; 4]> {
+        let mut names = Vec::new();
+        let mut rest = &png[8..];
+        while rest.len() >= 12 {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            names.push(rest[4..8].try_into().unwrap());
+            rest = &rest[12 + len..];
+        }
+
+        names
+    }
+
+    #[test]
+    fn empty_and_single_pixel_images_compress() {
+        for (width, height) in [(0, 5), (5, 0), (0, 0), (1, 1)] {
+            let mut compressor = Compressor::new(gradient(width, height, 4), CompressorOptions::new());
+            compressor.compress(0, None);
+
+            let img = compressor.reconstruct();
+            assert_eq!(img.data.len(), width * height * 4);
+
+            // PNGs can't be empty, they only need to fail without panicking
+            match encode_png(&img) {
+                Ok(_) => assert!(width > 0 && height > 0),
+                Err(err) => assert!(matches!(err, PngpartError::InvalidInput(_)), "{err:?}")
+            }
+        }
+    }
+
+    #[test]
+    fn read_errors_name_the_file() {
+        let path = Path::new("does/not/exist.png");
+        match read_image(path).err() {
+            Some(PngpartError::Io { path: err_path, .. }) => assert_eq!(err_path, path),
+            other => panic!("expected an I/O error, got {other:?}")
+        }
+    }
+
+    #[test]
+    fn strip_modes_keep_the_expected_chunks() {
+        let img = gradient(16, 16, 3);
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png, 16, 16);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.add_text_chunk("Author".into(), "someone".into()).unwrap();
+            encoder.add_ztxt_chunk("Comment".into(), "somewhere".into()).unwrap();
+
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_chunk(png::chunk::ChunkType(*b"eXIf"), b"MM\0*\0\0\0\x08\0\0").unwrap();
+            writer.write_chunk(png::chunk::ChunkType(*b"tIME"), &[0x07, 0xe8, 1, 2, 3, 4, 5]).unwrap();
+            writer.write_image_data(&img.data).unwrap();
+        }
+
+        let metadata = [*b"tEXt", *b"zTXt", *b"eXIf", *b"tIME"];
+        for (strip, kept) in [(StripMode::None, true), (StripMode::Safe, false), (StripMode::All, false)] {
+            let options = OptimizeOptions { strip, ..OptimizeOptions::default() };
+            let names = chunk_names(&optimize_png(&png, &options).unwrap());
+            for name in &metadata {
+                assert_eq!(names.contains(name), kept, "{strip:?} with {}", String::from_utf8_lossy(name));
+            }
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_images_round_trip() {
+        let data = (0..24 * 24 * 3).flat_map(|i: u32| ((i * 2731) as u16).to_be_bytes()).collect();
+        let img = Image { width: 24, height: 24, channels: 3, depth: BitDepth::Sixteen, data };
+
+        let decoded = decode_image(encode_png(&img).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.depth, BitDepth::Sixteen);
+        assert_eq!(decoded.data, img.data);
+
+        // Exact blocks reconstruct every sample, including the low bytes
+        let mut compressor = Compressor::new(decoded, CompressorOptions::new());
+        compressor.compress(0, None);
+        let output = decode_image(encode_png(&compressor.reconstruct()).unwrap().as_slice()).unwrap();
+        assert_eq!((output.channels, output.depth), (3, BitDepth::Sixteen));
+        assert_eq!(output.data, img.data);
+    }
+
+    #[test]
+    fn grayscale_images_stay_grayscale() {
+        // Noise over large patches, which the blocks smooth out
+        let noise = |i: u64| i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 61;
+        let data = (0..64 * 64).map(|i| (i % 64 / 16 * 40 + i / 1024 * 30 + noise(i)) as u8).collect();
+        let input = encode_png(&Image { width: 64, height: 64, channels: 1, depth: BitDepth::Eight, data }).unwrap();
+        let img = decode_image(input.as_slice()).unwrap();
+        assert_eq!(img.channels, 1);
+
+        let mut compressor = Compressor::new(img, CompressorOptions::new());
+        compressor.compress(64, None);
+        let output = encode_png(&compressor.reconstruct()).unwrap();
+        assert_eq!(decode_image(output.as_slice()).unwrap().channels, 1);
+        assert!(output.len() <= input.len(), "{} bytes grew to {}", input.len(), output.len());
+    }
+
+    #[test]
+    fn ssim_is_one_only_for_identical_images() {
+        for (width, height) in [(40, 30), (5, 3)] {
+            let img = gradient(width, height, 3);
+            assert!((ssim(&img, &img) - 1.0).abs() < 1e-9);
+
+            let mut compressor = Compressor::new(img.clone(), CompressorOptions::new());
+            compressor.compress(256, None);
+            let score = ssim(&img, &compressor.render());
+            assert!((0.0..1.0).contains(&score), "{width}x{height} scored {score}");
+            assert!((compressor.ssim() - score).abs() < 1e-6);
+        }
+    }
+
+    // The cut `split` makes of the whole image with the given options
+    fn first_cut(img: &Image, options: CompressorOptions, split: StandardSplit) -> Option<Cut> {
+        let compressor = Compressor::new(img.clone(), options);
+        let ctx = SplitContext { compressor: &compressor, depth: 1, measured: RefCell::new(Vec::new()) };
+        split.split(&ctx, &Bound::new(0, img.width, 0, img.height))
+    }
+
+    #[test]
+    fn standard_split_cuts_at_the_expected_positions() {
+        // Black on the first 3 of 8 columns and white on the rest
+        let data = (0..8 * 4).map(|i| if i % 8 < 3 { 0 } else { 255 }).collect();
+        let img = Image { width: 8, height: 4, channels: 1, depth: BitDepth::Eight, data };
+        let split = |mode, position| first_cut(&img, CompressorOptions::new(), StandardSplit { mode, position });
+
+        assert_eq!(split(SplitMode::Binary, CutPosition::Midpoint), Some(Cut::X(4)));
+        assert_eq!(split(SplitMode::Binary, CutPosition::OptimalCut), Some(Cut::X(3)));
+        assert_eq!(split(SplitMode::Quad, CutPosition::Midpoint), Some(Cut::Both(4, 2)));
+
+        // Every row is the same, so every cut along y leaves the same variance
+        // and the middle one is taken
+        assert_eq!(split(SplitMode::Quad, CutPosition::OptimalCut), Some(Cut::Both(3, 2)));
+    }
+
+    #[test]
+    fn split_context_respects_the_minimum_block_size() {
+        let img = gradient(8, 5, 3);
+        let options = CompressorOptions::new().min_block(3);
+        let compressor = Compressor::new(img.clone(), options.clone());
+        let ctx = SplitContext { compressor: &compressor, depth: 1, measured: RefCell::new(Vec::new()) };
+        assert_eq!(ctx.cuts_x(&Bound::new(0, 8, 0, 5)), Some(3..=5));
+        assert_eq!(ctx.cuts_y(&Bound::new(0, 8, 0, 5)), None);
+        assert_eq!(ctx.cuts_x(&Bound::new(2, 7, 0, 5)), None);
+
+        // Only the axis long enough is cut, even when splitting into quadrants
+        let quad = StandardSplit { mode: SplitMode::Quad, position: CutPosition::Midpoint };
+        assert_eq!(first_cut(&img, options, quad), Some(Cut::X(4)));
+
+        let mut compressor = Compressor::new(gradient(20, 13, 3), CompressorOptions::new().min_block(3));
+        compressor.compress(0, None);
+        for block in compressor.blocks() {
+            assert!(block.bound.width() >= 3 && block.bound.height() >= 3, "{:?}", block.bound);
+        }
+    }
+
+    #[test]
+    fn max_depth_limits_the_number_of_splits() {
+        for (mode, blocks) in [(SplitMode::Binary, 4), (SplitMode::Quad, 16)] {
+            let split = StandardSplit { mode, position: CutPosition::Midpoint };
+            let options = CompressorOptions::new().max_depth(Some(2)).split_strategy(split);
+            let mut compressor = Compressor::new(gradient(32, 32, 3), options);
+            compressor.compress(0, None);
+            assert_eq!(compressor.block_count(), blocks);
+        }
+    }
+
+    #[test]
+    fn compressed_bytes_decode_to_the_input_shape() {
+        for (channels, color_type) in [(1, ColorType::Gray), (3, ColorType::Rgb), (4, ColorType::Rgba)] {
+            let input = encode_png_fast(&gradient(48, 32, channels)).unwrap();
+            let options = CompressorOptions::new().tolerance(64);
+            let optimize = OptimizeOptions { keep_color_type: true, ..OptimizeOptions::default() };
+
+            let output = compress_bytes(&input, &options, &optimize).unwrap();
+            assert_eq!(compress_png(input.as_slice(), &options, &optimize).unwrap(), output);
+
+            let img = decode_image(output.as_slice()).unwrap();
+            assert_eq!((img.width, img.height, img.color_type()), (48, 32, color_type));
+        }
+    }
+
+    #[test]
+    fn successive_compressions_match_a_single_one() {
+        let img = gradient(40, 30, 3);
+        let mut once = Compressor::new(img.clone(), CompressorOptions::new());
+        once.compress(128, None);
+
+        let mut twice = Compressor::new(img, CompressorOptions::new());
+        twice.compress(256, None);
+        assert!(twice.block_count() < once.block_count());
+        twice.compress(128, None);
+        assert_eq!(twice.blocks(), once.blocks());
+    }
+
+    #[test]
+    fn blocks_tile_the_image_in_order() {
+        let (width, height) = (37, 23);
+        for mode in [SplitMode::Binary, SplitMode::Quad] {
+            let options = CompressorOptions::new().split_strategy(StandardSplit { mode, position: CutPosition::Midpoint });
+            let mut compressor = Compressor::new(gradient(width, height, 3), options);
+            for tolerance in [1024, 64, 0] {
+                compressor.compress(tolerance, None);
+                let blocks = compressor.blocks();
+
+                let mut covered = vec![0; width * height];
+                for block in &blocks {
+                    for y in block.bound.y_min..block.bound.y_max {
+                        for x in block.bound.x_min..block.bound.x_max {
+                            covered[y * width + x] += 1;
+                        }
+                    }
+                }
+                assert!(covered.iter().all(|&count| count == 1), "{mode:?} at {tolerance}");
+
+                let order = |block: &Block| (block.bound.y_min, block.bound.x_min);
+                assert!(blocks.windows(2).all(|pair| order(&pair[0]) < order(&pair[1])), "{mode:?} at {tolerance}");
+            }
+        }
+    }
+
+    #[test]
+    fn resuming_a_saved_state_matches_a_single_compression() {
+        let img = gradient(40, 30, 4);
+        let mut direct = Compressor::new(img.clone(), CompressorOptions::new());
+        direct.compress(64, None);
+
+        let mut state = Vec::new();
+        let mut saved = Compressor::new(img.clone(), CompressorOptions::new());
+        saved.compress(512, None);
+        saved.save_state(&mut state).unwrap();
+
+        let mut resumed = Compressor::resume(img, CompressorOptions::new(), state.as_slice()).unwrap();
+        assert_eq!(resumed.blocks(), saved.blocks());
+        resumed.compress(64, None);
+        assert_eq!(resumed.blocks(), direct.blocks());
+    }
+
+    #[test]
+    fn resume_rejects_another_image() {
+        let img = gradient(40, 30, 4);
+        let mut state = Vec::new();
+        let mut compressor = Compressor::new(img.clone(), CompressorOptions::new());
+        compressor.compress(512, None);
+        compressor.save_state(&mut state).unwrap();
+
+        let resume = |img| Compressor::resume(img, CompressorOptions::new(), state.as_slice()).err();
+        assert!(matches!(resume(gradient(30, 40, 4)), Some(StateError::Image)));
+
+        // Same size, but one sample differs
+        let mut edited = img.clone();
+        edited.data[0] ^= 1;
+        assert!(matches!(resume(edited), Some(StateError::Image)));
+        assert!(resume(img).is_none());
+    }
+
+    #[test]
+    fn steps_only_describe_real_splits() {
+        let mut compressor = Compressor::new(gradient(8, 8, 3), CompressorOptions::new());
+        let step = compressor.step().unwrap();
+        assert_eq!(step.bound, Bound::new(0, 8, 0, 8));
+        assert_eq!((step.blocks, compressor.split_count()), (2, 1));
+        assert!(matches!(step.cut, Cut::X(4) | Cut::Y(4)), "{:?}", step.cut);
+
+        // Stepping until the end gives the same blocks as compressing
+        while compressor.step().is_some() {}
+        let mut compressed = Compressor::new(gradient(8, 8, 3), CompressorOptions::new());
+        compressed.compress(0, None);
+        assert_eq!(compressor.blocks(), compressed.blocks());
+    }
+
+    #[test]
+    fn steps_end_when_no_block_can_be_split() {
+        // Without any variance
+        let flat = Image { width: 4, height: 4, channels: 3, depth: BitDepth::Eight, data: vec![9; 48].into() };
+        let mut compressor = Compressor::new(flat, CompressorOptions::new());
+        assert!(compressor.step().is_none());
+
+        // At the maximum depth, or too small, the block is set aside
+        for options in [CompressorOptions::new().max_depth(Some(0)), CompressorOptions::new().min_block(5)] {
+            let mut compressor = Compressor::new(gradient(8, 8, 3), options);
+            assert!(compressor.step().is_none());
+            assert_eq!((compressor.block_count(), compressor.split_count()), (1, 0));
+            assert_eq!(compressor.splittable_count(), 0);
+        }
+
+        // Blocks that can't be split are passed over for the ones that can
+        let mut compressor = Compressor::new(gradient(12, 8, 3), CompressorOptions::new().min_block(4));
+        let mut steps = 0;
+        while let Some(step) = compressor.step() {
+            assert!(step.bound.width() >= 8 || step.bound.height() >= 8, "{:?}", step.bound);
+            steps += 1;
+        }
+        assert_eq!(steps, compressor.split_count());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_lists_every_block() {
+        let mut compressor = Compressor::new(gradient(13, 7, 4), CompressorOptions::new());
+        compressor.compress(64, None);
+        let json: serde_json::Value = serde_json::from_str(&compressor.to_json()).unwrap();
+        assert_eq!((json["width"].as_u64(), json["height"].as_u64()), (Some(13), Some(7)));
+
+        let records = json["blocks"].as_array().unwrap();
+        assert_eq!(records.len(), compressor.block_count());
+        for (record, Block { bound, color: [r, g, b, a], .. }) in records.iter().zip(compressor.blocks()) {
+            let (x, y, w, h) = (bound.x_min, bound.y_min, bound.width(), bound.height());
+            assert_eq!(*record, serde_json::json!({ "x": x, "y": y, "w": w, "h": h, "r": r, "g": g, "b": b, "a": a }));
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn rgba_images_are_moved() {
+        let rgba = image::RgbaImage::from_fn(7, 5, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        let copy = Image::from_rgba_image(&rgba);
+        let pixels = rgba.as_raw().as_ptr();
+
+        let img = Image::from(rgba);
+        assert_eq!(img.data.as_ptr(), pixels);
+        assert_eq!((img.width, img.height, img.color_type()), (7, 5, ColorType::Rgba));
+        assert_eq!(img.data, copy.data);
+    }
+}
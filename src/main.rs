@@ -1,277 +1,3343 @@
-use std::cmp::Ordering;
-use std::io::BufWriter;
-use std::{env, collections::BinaryHeap};
-use std::fs::File;
-
-// TODO: replace with clap for more options
-//  - Oxipng settings (enabled, level)
-//  - Tolerance/iterations
-//  - Verbose (logging, timing)
-//  - Glob support
-fn get_arguments() -> (Box<str>, Box<str>) {
-    let mut args = env::args();
-    let name = args.next().unwrap_or(String::from(env!("CARGO_CRATE_NAME")));
-
-    let in_file = match args.next() {
-        Some(in_file) => in_file,
-        None => {
-            eprintln!("ERROR: no input file");
-            eprintln!("USAGE: {name} <input file> <output file>");
-            std::process::exit(1);
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::num::NonZeroU8;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use clap::error::ContextKind;
+use clap::parser::ValueSource;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use pngpart::{
+    decode_animation, decode_image, encode_animation, encode_indexed_png, encode_png_with, is_jpeg, optimize_png,
+    read_palette, Animation, BilinearFill, Bound, ColorType, Compressor, CompressorOptions, DEFAULT_OPT_LEVEL,
+    EncodeError, GifEncoder, Image, MeanFill, OptimizeOptions, PngpartError, Region, SplitMix64, StandardSplit,
+    StopReason
+};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+#[derive(Parser)]
+#[command(
+    version, about = "Partition-based lossy PNG compression",
+    args_conflicts_with_subcommands = true, subcommand_negates_reqs = true, disable_help_subcommand = true
+)]
+struct Args {
+    /// Input PNG files, `-` to read from stdin
+    ///
+    /// Glob patterns such as `shots/*.png` are expanded, quote them so the
+    /// shell doesn't expand them first.
+    ///
+    /// Without --output, --output-dir or --in-place, the last path is where
+    /// the outputs go when it is an existing directory, like with `cp`.
+    /// Otherwise, it is the output file when exactly two paths are given
+    /// without --name-template and it isn't a pattern, and any more are an
+    /// error.
+    ///
+    /// Files that don't end with `.png` are skipped when they match a pattern.
+    /// The outputs in --output-dir keep the directories of their input below
+    /// the start of the pattern, such as `ui/icons/a.png` for `assets/**/*.png`.
+    #[arg(value_name = "INPUT", required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Output PNG file, `-` to write to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Write every output to this directory, named after its input file
+    ///
+    /// The directory is created if it doesn't exist.
+    #[arg(long, visible_alias = "out-dir", conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+
+    /// Name the outputs after a template instead
+    ///
+    /// The placeholders `{stem}` and `{ext}` are replaced with the name and
+    /// extension of the input file, `{tolerance}` with the tolerance and
+    /// `{blocks}` with the number of blocks of the compressed image. Outputs
+    /// are placed in the output directory, or next to their inputs if there is
+    /// none.
+    #[arg(long, conflicts_with = "output", value_parser = parse_name_template)]
+    name_template: Option<NameTemplate>,
+
+    /// Allow overwriting the input with its output
+    ///
+    /// The output defaults to the input. It is written to a temporary file
+    /// first, which then replaces the input so that a failure never leaves it
+    /// half written.
+    #[arg(long, conflicts_with_all = ["output_dir", "no_clobber"])]
+    in_place: bool,
+
+    /// Skip the inputs whose output already exists
+    #[arg(long)]
+    no_clobber: bool,
+
+    /// Compress every PNG in the input directory and its subdirectories
+    ///
+    /// The output is a directory which mirrors the structure of the input.
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Composite the inputs over this color, such as `#ffffff`, `#fff` or
+    /// `white`, so that the outputs are opaque
+    ///
+    /// This happens before anything else, so the alpha channel no longer
+    /// takes part in the variance of the blocks and the outputs are written
+    /// without one.
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    background: Option<[u8; 3]>,
+
+    /// Only compress the rectangle of the input that starts `X` pixels from
+    /// the left and `Y` from the top, and is `W` by `H` pixels large
+    ///
+    /// The output has the size of the rectangle. Inputs that the rectangle
+    /// doesn't fit in fail rather than being cropped to a smaller size.
+    #[arg(long, value_name = "X,Y,WxH", value_parser = parse_crop)]
+    crop: Option<Crop>,
+
+    /// Use a lower tolerance for the blocks that overlap the rectangle that
+    /// starts `X` pixels from the left and `Y` from the top, and is `W` by `H`
+    /// pixels large
+    ///
+    /// This keeps more detail in faces or text than in the background, and
+    /// can be repeated. The lowest tolerance applies where rectangles overlap.
+    /// They are placed on the input, before it is cropped or resized.
+    #[arg(long, value_name = "X,Y,WxH:TOLERANCE", value_parser = parse_roi)]
+    roi: Vec<Roi>,
+
+    /// Scale the input to this size before compressing it, such as `800x600`,
+    /// `800x` or `x600` to keep the aspect ratio, or `50%`
+    ///
+    /// Inputs are only made smaller unless --upscale is given. They are
+    /// cropped first with --crop.
+    #[arg(long, value_name = "SIZE", value_parser = parse_resize)]
+    resize: Option<Resize>,
+
+    /// Also scale up the inputs that are smaller than the size of --resize
+    #[arg(long, requires = "resize")]
+    upscale: bool,
+
+    /// Maximum variance allowed for a block [default: 8, or 128 with the
+    /// total variance mode]
+    ///
+    /// The variance of a block is the mean squared deviation of every channel
+    /// of every pixel from the block's average color. Blocks are split until
+    /// no block exceeds this value, so higher values flatten the image more
+    /// aggressively and lower values keep more detail.
+    ///
+    /// The unit is a squared channel value from 0 to 255.
+    ///
+    /// Several tolerances can be given, such as `512,128,32`, to write the
+    /// image at each of them in a single run. The outputs are named after
+    /// their tolerance, such as `out-t512.png`, unless the name template
+    /// already is.
+    #[arg(
+        short, long, allow_negative_numbers = true, value_delimiter = ',', num_args = 1,
+        value_parser = parse_tolerance
+    )]
+    tolerance: Vec<u64>,
+
+    /// Pick the lowest tolerance whose output fits in this many bytes
+    ///
+    /// Sizes such as `20kb` (1000 bytes) or `2mib` (1048576 bytes) are
+    /// accepted. The image is compressed and encoded again for every tolerance
+    /// that is tried, so this is much slower. If even a single block doesn't
+    /// fit, that is written instead.
+    #[arg(long, conflicts_with = "tolerance", value_parser = parse_size)]
+    target_size: Option<usize>,
+
+    /// Give up searching for the target size after this many attempts, and
+    /// keep the best one
+    #[arg(long, default_value_t = 12, requires = "target_size", value_parser = parse_positive)]
+    target_size_passes: usize,
+
+    /// Split blocks until the peak signal-to-noise ratio of the output
+    /// reaches this many decibels, instead of using a tolerance
+    ///
+    /// The PSNR measures the error over the whole image, where the tolerance
+    /// bounds the error of every block. Typical values are 30 to 50, the
+    /// higher the closer to the input.
+    #[arg(long, value_name = "DB", conflicts_with_all = ["tolerance", "target_size"], value_parser = parse_psnr)]
+    target_psnr: Option<f64>,
+
+    /// How the variance of a block is measured
+    ///
+    /// The total variance mode sums the squared deviations instead of
+    /// averaging them, so an error spread over a large block counts more than
+    /// the same error in a small one. This is how the tolerance was measured
+    /// before the mean became the default.
+    #[arg(long, value_enum, default_value_t = VarianceMode::Mean)]
+    variance_mode: VarianceMode,
+
+    /// The color space the variance of a block is measured in
+    ///
+    /// With lab, the tolerance is in squared CIELAB units, where a distance of
+    /// about 2.3 is just noticeable, and the blocks are split where the eye
+    /// sees the most detail. Converting the input takes about as long as
+    /// preparing the compression itself. With luma, only the brightness is
+    /// measured, which gives fewer and larger blocks in colorful areas.
+    #[arg(long, value_enum, default_value_t = Metric::Srgb)]
+    metric: Metric,
+
+    /// Which color the blocks are painted with
+    ///
+    /// The median keeps edges crisper, since a few pixels on the other side
+    /// of an edge don't tint the whole block, but it is slower to compute.
+    #[arg(long, value_enum, default_value_t = Aggregate::Mean)]
+    aggregate: Aggregate,
+
+    /// How the pixels of the blocks are painted from their color
+    ///
+    /// A bilinear fill paints every block with the gradient that best matches
+    /// its pixels, which follows smooth content more closely with the same
+    /// blocks. The output usually compresses less well, and SVG output is
+    /// always flat.
+    #[arg(long, alias = "fit", value_enum, default_value_t = Fill::Mean)]
+    fill: Fill,
+
+    /// Average the colors of the blocks in linear light
+    ///
+    /// Averaging the gamma-encoded channels of the input makes edges and
+    /// gradients darker and duller than they look, this gives the colors the
+    /// eye would blend them to. It has no effect on the median.
+    #[arg(long)]
+    linear: bool,
+
+    /// Weight every pixel by its alpha, so that transparent pixels count
+    /// neither in the color of a block nor in its variance
+    ///
+    /// Blocks that are transparent throughout are then never split, whatever
+    /// colors the input hides behind the alpha. This makes sprites with large
+    /// transparent areas much smaller.
+    #[arg(long)]
+    alpha_weighted: bool,
+
+    /// Multiply the squared deviations of the red, green, blue and alpha
+    /// channels by these weights in the variance of a block
+    ///
+    /// A weight of 0 ignores the channel, and a weight of 2 makes it count
+    /// twice as much. Gray inputs use the mean of the first three weights for
+    /// their gray channel, and with --metric lab they weigh L, a and b.
+    #[arg(
+        long, value_name = "R,G,B,A", value_delimiter = ',', num_args = 1, default_value = "1,1,1,1",
+        value_parser = parse_weight
+    )]
+    weights: Vec<f64>,
+
+    /// Stop splitting once the image is made of this many blocks
+    ///
+    /// Splitting also stops when the tolerance is reached, whichever happens
+    /// first.
+    #[arg(long, value_parser = parse_positive)]
+    max_blocks: Option<usize>,
+
+    /// Split blocks until the image is made of this many blocks, instead of
+    /// using a tolerance
+    ///
+    /// The quad split mode adds three blocks at a time, so the count may be
+    /// exceeded by up to two. Fewer blocks are made if --min-block-size or
+    /// --max-depth keep the blocks from being split any further.
+    #[arg(
+        long, value_name = "N", value_parser = parse_positive,
+        conflicts_with_all = ["tolerance", "target_size", "target_psnr", "max_blocks"]
+    )]
+    target_blocks: Option<usize>,
+
+    /// Never split a block into parts narrower or shorter than this many pixels
+    ///
+    /// Blocks that are at most this size on both axes are never split, which
+    /// keeps noisy images from being divided into tiny blocks.
+    #[arg(long, visible_alias = "min-block", default_value_t = 1, value_parser = parse_positive)]
+    min_block_size: usize,
+
+    /// Never split a block more than this many times
+    ///
+    /// The whole image is at depth 0 and every split increases the depth of
+    /// its parts by one. This bounds the number of blocks to `2^depth`, or
+    /// `4^depth` with the quad split mode, whatever the image content.
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// How blocks are divided when they are split
+    #[arg(long, value_enum, default_value_t = SplitMode::Binary)]
+    split_mode: SplitMode,
+
+    /// Where blocks are cut when they are split
+    #[arg(long, value_enum, default_value_t = CutPosition::Midpoint)]
+    split_strategy: CutPosition,
+
+    /// Oxipng optimization preset, from 0 (fastest) to 6 (smallest)
+    ///
+    /// Every level tries more filters and compression settings than the one
+    /// before, so the highest levels can take significantly longer on large
+    /// images for a few percent smaller outputs.
+    #[arg(long, default_value_t = DEFAULT_OPT_LEVEL, value_parser = clap::value_parser!(u8).range(0..=6))]
+    opt_level: u8,
+
+    /// Compress the optimized image with Zopfli, which is slower but smaller
+    ///
+    /// The number of iterations is given as `--zopfli=N` and defaults to 15,
+    /// large images may need fewer to finish in a reasonable time.
+    #[arg(
+        long, value_name = "ITERATIONS", num_args = 0..=1, require_equals = true,
+        default_missing_value = "15", conflicts_with = "no_optimize"
+    )]
+    zopfli: Option<NonZeroU8>,
+
+    /// Which metadata chunks are removed from the optimized image
+    ///
+    /// `all` guarantees that no text, EXIF or timestamp chunk is left, `safe`
+    /// only keeps the chunks that affect how the image is displayed.
+    #[arg(long, value_enum, default_value_t = StripMode::None, conflicts_with = "no_optimize")]
+    strip: StripMode,
+
+    /// Interlace the output so it can be displayed while it is downloaded
+    ///
+    /// Interlaced images are usually larger. Oxipng does the interlacing, so
+    /// this can't be used with --no-optimize.
+    #[arg(long, conflicts_with = "no_optimize")]
+    interlace: bool,
+
+    /// The format of the output
+    ///
+    /// SVG draws every block as a rectangle, so the output scales to any size.
+    /// JSON lists the position, size and color of every block for other
+    /// programs to use, it needs the `serde` feature. The options about PNG
+    /// encoding are ignored with both.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Png)]
+    format: OutputFormat,
+
+    /// Which channels the output is written with
+    ///
+    /// `auto` drops the alpha channel of opaque images and the colors of
+    /// images made only of grays. The other values fail if they would lose
+    /// information, unless --force is given, and keep oxipng from changing the
+    /// color type.
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color_type: ColorChoice,
+
+    /// Convert the output to the requested color type even if it loses
+    /// information
+    ///
+    /// Colors are replaced with their luma and the alpha channel is dropped.
+    #[arg(long, requires = "color_type")]
+    force: bool,
+
+    /// When the output is written with a palette of up to 256 colors
+    ///
+    /// `auto` writes indexed inputs with a palette again. The output keeps its
+    /// own colors if there are at most 256 of them, and they are mapped to the
+    /// closest colors of the input's palette otherwise. `force` also writes
+    /// the other inputs with a palette, quantizing their colors if there are
+    /// too many. Oxipng may still pick a palette for small outputs with
+    /// `never`.
+    #[arg(long, value_enum, default_value_t = PaletteChoice::Auto, conflicts_with = "color_type")]
+    palette: PaletteChoice,
+
+    /// Write the output with a palette of at most this many colors, from 1 to
+    /// 256
+    ///
+    /// Colors that are close are merged until few enough are left, which
+    /// barely shows since most blocks only differ slightly from some others.
+    /// Outputs that already have few enough colors keep them.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..=256), conflicts_with = "color_type")]
+    max_colors: Option<u16>,
+
+    /// Write the encoded image without optimizing it with oxipng
+    ///
+    /// This is much faster, but the output is larger.
+    #[arg(long, conflicts_with = "opt_level")]
+    no_optimize: bool,
+
+    /// How hard the PNG encoder compresses the image before it is optimized
+    ///
+    /// Defaults to fast, or best with --no-optimize since the encoder is
+    /// then the only compressor.
+    #[arg(long, value_enum)]
+    png_compression: Option<PngCompression>,
+
+    /// Number of threads used to prepare the compression and run oxipng
+    ///
+    /// 0 uses every core, 1 makes everything sequential. It also caps --jobs.
+    /// Without the `parallel` feature everything is sequential, and only 0
+    /// and 1 are accepted.
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Number of files compressed at once
+    ///
+    /// 0 compresses as many files as there are cores. The messages about a
+    /// file are printed together once it is done, and the progress bars are
+    /// hidden. Without the `parallel` feature the files are compressed one at
+    /// a time.
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Keep running and compress the inputs again whenever they change
+    ///
+    /// The inputs are checked every --poll-interval, and compressed once they
+    /// stop changing between two checks. Outputs are replaced at once so that
+    /// a viewer never reads them half written. Press Ctrl-C to stop.
+    #[arg(long, conflicts_with_all = ["in_place", "no_clobber"])]
+    watch: bool,
+
+    /// How often the inputs are checked for changes with --watch, in
+    /// milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 500, requires = "watch", value_parser = parse_positive)]
+    poll_interval: usize,
+
+    /// Only compress the images in memory and print statistics about the
+    /// results instead of writing them
+    ///
+    /// The output becomes optional. The estimated size is the size of the
+    /// image before it is optimized, which is an upper bound of the final size.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write statistics about every compressed file as JSON, to stdout or to
+    /// the given file with `--stats=FILE`
+    ///
+    /// Every file is described by an object on its own line, with the same
+    /// keys whatever the options. Messages are printed to stderr, and so is the
+    /// report of --dry-run when the statistics are written to stdout.
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+    stats: Option<PathBuf>,
+
+    /// Also write how far every pixel of the output is from the input to this
+    /// file, as a grayscale PNG
+    ///
+    /// Bright pixels show where the blocks are too coarse. Only a single input
+    /// can be given.
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    diff: Option<PathBuf>,
+
+    /// Write an animated GIF of the image being refined to this file, from a
+    /// single block to the output
+    ///
+    /// A frame is taken every --animate-stride splits, and the blocks are
+    /// outlined in it with --show-grid. Only a single input can be given.
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    animate: Option<PathBuf>,
+
+    /// How many splits are made between two frames of --animate
+    #[arg(long, value_name = "SPLITS", default_value_t = 1000, requires = "animate", value_parser = parse_positive)]
+    animate_stride: usize,
+
+    /// How long every frame of --animate is shown, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 100, requires = "animate")]
+    animate_delay: u64,
+
+    /// Scatter the colors on both sides of the edges between blocks over
+    /// this many pixels, 4 if no radius is given
+    ///
+    /// This softens the blocky look and the banding of gradients without
+    /// adding colors, but the scattered pixels make the output larger. Only
+    /// PNG outputs are dithered.
+    #[arg(
+        long, value_name = "RADIUS", num_args = 0..=1, require_equals = true, default_missing_value = "4",
+        value_parser = parse_positive
+    )]
+    dither: Option<usize>,
+
+    /// Draw the edges of the blocks over PNG outputs, to see how the image is
+    /// partitioned
+    ///
+    /// The lines are 1 pixel wide and drawn in the color of --grid-color.
+    #[arg(long)]
+    show_grid: bool,
+
+    /// The color of the lines of --show-grid, such as `#ff0000`, `#f00` or
+    /// `red`
+    #[arg(long, value_name = "COLOR", default_value = "black", value_parser = parse_color, requires = "show_grid")]
+    grid_color: [u8; 3],
+
+    /// Seed the random parts of the compression, so that a result can be
+    /// reproduced
+    ///
+    /// The same seed, input and options always give the same output. A random
+    /// seed is used by default, printed with --verbose.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Print additional information about the compression, including how
+    /// long every stage takes
+    ///
+    /// Repeat it (`-vv`) to also follow the progression of the compression.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Read the default options from this TOML file instead of `pngpart.toml`
+    ///
+    /// The keys are the names of the long options, such as `tolerance = 4` or
+    /// `no-optimize = true`. The options given on the command line take
+    /// precedence over the file, which is looked for in the current directory
+    /// by default.
+    ///
+    /// Every option can also be set with an environment variable named after
+    /// it, such as `PNGPART_TOLERANCE=4`, which takes precedence over the file
+    /// but not over the command line.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Ignore `pngpart.toml`
+    #[arg(long, conflicts_with = "config")]
+    no_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Print the script that completes the options of pngpart in a shell
+    ///
+    /// For example, `pngpart completions bash > /etc/bash_completion.d/pngpart`
+    /// installs it for bash.
+    Completions {
+        shell: clap_complete::Shell
+    }
+}
+
+fn parse_tolerance(s: &str) -> Result<u64, String> {
+    if s.starts_with('-') {
+        return Err(String::from("must not be negative"));
+    }
+
+    s.parse().map_err(|err| format!("{err} (expected an integer from 0 to {})", u64::MAX))
+}
+
+fn parse_positive(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err(String::from("must be at least 1")),
+        Ok(n) => Ok(n),
+        Err(err) => Err(format!("{err}"))
+    }
+}
+
+fn parse_psnr(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(psnr) if psnr.is_finite() => Ok(psnr),
+        Ok(_) => Err(String::from("must be finite")),
+        Err(err) => Err(format!("{err}"))
+    }
+}
+
+fn parse_weight(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(weight) if weight >= 0.0 && weight.is_finite() => Ok(weight),
+        Ok(_) => Err(String::from("weights must be positive or 0")),
+        Err(err) => Err(format!("{err}"))
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let lower = s.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        unit => return Err(format!("unknown unit `{unit}` (expected b, kb, kib, mb or mib)"))
+    };
+
+    let size: usize = digits.parse().map_err(|err| format!("{err}"))?;
+    size.checked_mul(unit).ok_or_else(|| String::from("the size is too large"))
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let named = match s.to_ascii_lowercase().as_str() {
+        "black" => Some([0, 0, 0]),
+        "white" => Some([255, 255, 255]),
+        "gray" | "grey" => Some([128, 128, 128]),
+        "silver" => Some([192, 192, 192]),
+        "red" => Some([255, 0, 0]),
+        "green" => Some([0, 128, 0]),
+        "blue" => Some([0, 0, 255]),
+        "yellow" => Some([255, 255, 0]),
+        "cyan" => Some([0, 255, 255]),
+        "magenta" => Some([255, 0, 255]),
+        _ => None
+    };
+
+    if let Some(color) = named {
+        return Ok(color);
+    }
+
+    let expected = "expected `#rrggbb`, `#rgb` or a color name such as `white`";
+    let hex = s.strip_prefix('#').ok_or(expected)?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(String::from(expected));
+    }
+
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|err| format!("{err}"));
+    match hex.len() {
+        3 => {
+            // Every digit is repeated, `#fa0` is `#ffaa00`
+            let digit = |i: usize| channel(&hex[i..i + 1]).map(|c| c * 17);
+            Ok([digit(0)?, digit(1)?, digit(2)?])
+        },
+        6 => Ok([channel(&hex[0..2])?, channel(&hex[2..4])?, channel(&hex[4..6])?]),
+        _ => Err(String::from(expected))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Crop {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize
+}
+
+// Where the frames of --animate are written, and how often they are taken
+struct Animate {
+    path: PathBuf,
+    stride: usize,
+    delay: Duration
+}
+
+#[derive(Clone, Copy)]
+struct Roi {
+    rect: Crop,
+    tolerance: u64
+}
+
+#[derive(Clone, Copy)]
+enum Resize {
+    // A fraction of the size of the input
+    Scale(f64),
+
+    // A side that is left out keeps the aspect ratio
+    Size(Option<usize>, Option<usize>)
+}
+
+impl Resize {
+    // The size of an image of `width` by `height` pixels once it is resized,
+    // which is at least 1x1
+    fn apply(self, width: usize, height: usize) -> (usize, usize) {
+        let scale = |n: usize, factor: f64| ((n as f64 * factor).round() as usize).max(1);
+        match self {
+            Self::Scale(factor) => (scale(width, factor), scale(height, factor)),
+            Self::Size(Some(w), Some(h)) => (w, h),
+            Self::Size(Some(w), None) => (w, scale(height, w as f64 / width as f64)),
+            Self::Size(None, Some(h)) => (scale(width, h as f64 / height as f64), h),
+            Self::Size(None, None) => (width, height)
+        }
+    }
+}
+
+fn parse_resize(s: &str) -> Result<Resize, String> {
+    let expected = "expected `WxH`, `Wx`, `xH` or a percentage such as `50%`";
+    if let Some(percent) = s.strip_suffix('%') {
+        return match percent.trim().parse::<f64>() {
+            Ok(percent) if percent > 0.0 && percent.is_finite() => Ok(Resize::Scale(percent / 100.0)),
+            Ok(_) => Err(String::from("the percentage must be positive")),
+            Err(err) => Err(format!("{err} ({expected})"))
+        };
+    }
+
+    let (width, height) = s.split_once(['x', 'X']).ok_or(expected)?;
+    let side = |n: &str| match n.trim() {
+        "" => Ok(None),
+        n => match n.parse() {
+            Ok(0) => Err(String::from("the size must be at least 1x1")),
+            Ok(n) => Ok(Some(n)),
+            Err(err) => Err(format!("{err} ({expected})"))
+        }
+    };
+
+    match (side(width)?, side(height)?) {
+        (None, None) => Err(String::from(expected)),
+        (width, height) => Ok(Resize::Size(width, height))
+    }
+}
+
+fn parse_crop(s: &str) -> Result<Crop, String> {
+    let expected = "expected `X,Y,WxH`, such as `0,0,640x480`";
+    let (x, rest) = s.split_once(',').ok_or(expected)?;
+    let (y, size) = rest.split_once(',').ok_or(expected)?;
+    let (width, height) = size.split_once(['x', 'X']).ok_or(expected)?;
+
+    let number = |n: &str| n.trim().parse::<usize>().map_err(|err| format!("{err} ({expected})"));
+    let crop = Crop { x: number(x)?, y: number(y)?, width: number(width)?, height: number(height)? };
+    if crop.width == 0 || crop.height == 0 {
+        return Err(String::from("the size must be at least 1x1"));
+    }
+
+    Ok(crop)
+}
+
+fn parse_roi(s: &str) -> Result<Roi, String> {
+    let (rect, tolerance) = s.rsplit_once(':').ok_or("expected `X,Y,WxH:TOLERANCE`, such as `0,0,640x480:2`")?;
+    let tolerance = tolerance.trim().parse().map_err(|err| format!("invalid tolerance: {err}"))?;
+    Ok(Roi { rect: parse_crop(rect)?, tolerance })
+}
+
+#[derive(Clone)]
+enum TemplatePart {
+    Text(String),
+    Stem,
+    Ext,
+    Tolerance,
+    Blocks
+}
+
+#[derive(Clone)]
+struct NameTemplate {
+    parts: Vec<TemplatePart>
+}
+
+impl NameTemplate {
+    fn uses_blocks(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, TemplatePart::Blocks))
+    }
+
+    fn uses_tolerance(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, TemplatePart::Tolerance))
+    }
+
+    // Whether the name is only known once the image is compressed, which is
+    // also the case of the tolerance when it is searched for
+    fn depends_on_result(&self, searches_tolerance: bool) -> bool {
+        self.uses_blocks() || (searches_tolerance && self.uses_tolerance())
+    }
+
+    fn expand(&self, input: &Path, tolerance: u64, blocks: usize) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Text(text) => name.push_str(text),
+                TemplatePart::Stem => name.push_str(&input.file_stem().unwrap_or_default().to_string_lossy()),
+                TemplatePart::Ext => name.push_str(&input.extension().unwrap_or_default().to_string_lossy()),
+                TemplatePart::Tolerance => name.push_str(&tolerance.to_string()),
+                TemplatePart::Blocks => name.push_str(&blocks.to_string())
+            }
+        }
+
+        name
+    }
+}
+
+fn parse_name_template(s: &str) -> Result<NameTemplate, String> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(TemplatePart::Text(String::from(&rest[..start])));
+        }
+
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(String::from("unclosed `{`"))
+        };
+
+        parts.push(match &rest[start + 1..end] {
+            "stem" => TemplatePart::Stem,
+            "ext" => TemplatePart::Ext,
+            "tolerance" => TemplatePart::Tolerance,
+            "blocks" => TemplatePart::Blocks,
+            name => return Err(format!(
+                "unknown placeholder `{{{name}}}` (expected one of {{stem}}, {{ext}}, {{tolerance}}, {{blocks}})"
+            ))
+        });
+
+        rest = &rest[end + 1..];
+    }
+
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Text(String::from(rest)));
+    }
+
+    if parts.is_empty() {
+        return Err(String::from("the template is empty"));
+    }
+
+    Ok(NameTemplate { parts })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Png,
+    Svg,
+    #[cfg(feature = "serde")]
+    Json
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Svg => "svg",
+            #[cfg(feature = "serde")]
+            Self::Json => "json"
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Fill {
+    #[value(alias = "constant")]
+    Mean,
+    Bilinear
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum PaletteChoice {
+    Auto,
+    Force,
+    Never
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba
+}
+
+impl From<ColorType> for ColorChoice {
+    fn from(color_type: ColorType) -> Self {
+        match color_type {
+            ColorType::Gray => Self::Gray,
+            ColorType::GrayAlpha => Self::GrayAlpha,
+            ColorType::Rgb => Self::Rgb,
+            ColorType::Rgba => Self::Rgba
+        }
+    }
+}
+
+// The library doesn't depend on clap, so its option enums are mirrored here
+// with the same variants, which give the values their names and help
+macro_rules! mirror_enum {
+    ($name:ident { $($(#[$doc:meta])* $variant:ident),* }) => {
+        #[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+        enum $name {
+            $($(#[$doc])* $variant),*
+        }
+
+        impl From<$name> for pngpart::$name {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => Self::$variant),*
+                }
+            }
+        }
+
+        impl From<pngpart::$name> for $name {
+            fn from(value: pngpart::$name) -> Self {
+                match value {
+                    $(pngpart::$name::$variant => Self::$variant),*
+                }
+            }
+        }
+    };
+}
+
+mirror_enum!(StripMode {
+    /// Keep every chunk
+    None,
+
+    /// Remove the chunks that don't affect how the image is displayed, but
+    /// keep color profiles and the physical size
+    Safe,
+
+    /// Remove every ancillary chunk, including text and timestamps, but keep
+    /// the frames of animations
+    All
+});
+
+mirror_enum!(PngCompression {
+    /// Barely compress, which is enough when oxipng optimizes the image
+    Fast,
+
+    /// The encoder's default compression
+    Default,
+
+    /// Compress as much as the encoder can, for when oxipng is skipped
+    Best
+});
+
+mirror_enum!(VarianceMode {
+    /// The mean squared deviation of every channel of every pixel, which
+    /// gives the tolerance the same meaning for blocks of any size
+    Mean,
+
+    /// The summed squared deviation over the whole block, so larger blocks
+    /// are split before smaller ones with the same error
+    Total
+});
+
+mirror_enum!(Metric {
+    /// The channels of the image as they are stored
+    Srgb,
+
+    /// CIELAB, where the same distance looks about as different anywhere, so
+    /// dark areas aren't split more than the eye can tell apart. Every pixel
+    /// is converted and a second summed-area table is built, which takes
+    /// about twice the time and memory before the compression starts
+    Lab,
+
+    /// The Rec. 709 luminance of the channels alone, so that areas of the
+    /// same brightness aren't split however colorful they are. This needs a
+    /// second summed-area table too, a quarter of the size of the first one
+    Luma
+});
+
+mirror_enum!(Aggregate {
+    /// The mean of every channel, which gives the least squared error
+    Mean,
+
+    /// The median of every channel, which keeps a few pixels on the other
+    /// side of an edge from tinting the whole block. This is slower, since the
+    /// pixels of every new block are read again
+    Median
+});
+
+mirror_enum!(SplitMode {
+    /// Halve the block along the axis that leaves the least variance
+    Binary,
+
+    /// Divide the block into four quadrants
+    Quad
+});
+
+mirror_enum!(CutPosition {
+    /// Cut the block in the middle
+    Midpoint,
+
+    /// Cut the block where the parts have the least variance, this is slower
+    /// but needs fewer blocks for the same tolerance
+    OptimalCut
+});
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug
+}
+
+thread_local! {
+    // The messages about the file being compressed, when several files are
+    // compressed at once. They are printed together once the file is done,
+    // rather than interleaved with the messages about the other files
+    static MESSAGES: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Prints a message to stderr, or keeps it with the messages about the file
+// being compressed
+macro_rules! message {
+    ($($arg:tt)*) => {
+        MESSAGES.with_borrow_mut(|messages| match messages {
+            Some(messages) => {
+                messages.push_str(&format!($($arg)*));
+                messages.push('\n');
+            },
+            None => eprintln!($($arg)*)
+        })
+    };
+}
+
+// Prints an informational message to stderr if the verbosity is at least
+// `$level`, errors are always printed with `message!` instead
+macro_rules! log {
+    ($verbosity:expr, $level:ident, $($arg:tt)*) => {
+        if $verbosity >= Verbosity::$level {
+            message!($($arg)*);
+        }
+    };
+}
+
+struct Cli {
+    // Pairs of input and output paths
+    files: Vec<(PathBuf, PathBuf)>,
+
+    // Errors that happened while collecting the input files
+    errors: usize,
+
+    // Whether the parent directories of the outputs should be created
+    create_dirs: bool,
+
+    // The color the inputs are composited over
+    background: Option<[u8; 3]>,
+
+    // The rectangle of the inputs that is compressed
+    crop: Option<Crop>,
+    roi: Vec<Roi>,
+    resize: Option<Resize>,
+    upscale: bool,
+
+    // Replaces the file name of the outputs once the image is compressed
+    name_template: Option<NameTemplate>,
+
+    in_place: bool,
+    no_clobber: bool,
+
+    tolerance: u64,
+
+    // The tolerances above `tolerance` at which the image is also written,
+    // from the highest to the lowest
+    snapshots: Vec<u64>,
+    target_size: Option<usize>,
+    target_size_passes: usize,
+    target_psnr: Option<f64>,
+    variance_mode: VarianceMode,
+    metric: Metric,
+    aggregate: Aggregate,
+    fill: Fill,
+    linear: bool,
+    alpha_weighted: bool,
+    weights: [f64; 4],
+    max_blocks: Option<usize>,
+    target_blocks: Option<usize>,
+    min_block_size: usize,
+    max_depth: Option<usize>,
+    split_mode: SplitMode,
+    split_strategy: CutPosition,
+    format: OutputFormat,
+
+    // `None` picks the smallest color type that doesn't lose information
+    color_type: Option<ColorType>,
+    force: bool,
+    palette: PaletteChoice,
+    max_colors: Option<usize>,
+    optimize: Option<OptimizeOptions>,
+    png_compression: PngCompression,
+    #[cfg(feature = "parallel")]
+    threads: usize,
+    jobs: usize,
+    dry_run: bool,
+
+    // How often the inputs are checked for changes, if they are watched
+    watch: Option<Duration>,
+    dither: Option<usize>,
+
+    // The color of the edges of the blocks, if they are drawn
+    grid: Option<[u8; 3]>,
+    seed: u64,
+    stats: Option<PathBuf>,
+    diff: Option<PathBuf>,
+    animate: Option<Animate>,
+    verbosity: Verbosity,
+}
+
+fn arguments_error(kind: clap::error::ErrorKind, message: impl std::fmt::Display) -> clap::Error {
+    Args::command().error(kind, message)
+}
+
+fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+// Expands a pattern into the PNG files it matches, along with their path below
+// the directories before the first wildcard. The outputs mirror these paths,
+// so that `assets/**/*.png` keeps the subdirectories of `assets`
+fn expand_input(verbosity: Verbosity, input: &Path) -> Result<Vec<(PathBuf, PathBuf)>, clap::Error> {
+    let name = |path: &Path| PathBuf::from(path.file_name().unwrap_or_default());
+
+    // Only expand actual patterns so a missing file is reported as such
+    let pattern = match input.to_str() {
+        Some(pattern) if is_pattern(pattern) => pattern,
+        _ => return Ok(vec![(input.to_path_buf(), name(input))])
+    };
+
+    // The matches don't start with `./` even if the pattern does
+    let base: PathBuf = input.components()
+        .take_while(|component| !is_pattern(&component.as_os_str().to_string_lossy()))
+        .filter(|component| *component != std::path::Component::CurDir)
+        .collect();
+
+    let paths = match glob::glob(pattern) {
+        Ok(paths) => paths,
+        Err(err) => return Err(arguments_error(
+            clap::error::ErrorKind::InvalidValue,
+            format!("invalid pattern `{pattern}`: {err}")
+        ))
+    };
+
+    let mut inputs = Vec::new();
+    for path in paths {
+        match path {
+            // Recursive patterns also match the directories they go through
+            Ok(path) if path.is_dir() => {},
+            Ok(path) if !is_input(&path) => {
+                log!(verbosity, Normal, "WARNING: Skipping `{}`: not a PNG file", path.display());
+            },
+            Ok(path) => {
+                let relative = path.strip_prefix(&base).map_or_else(|_| name(&path), Path::to_path_buf);
+                inputs.push((path, relative));
+            },
+            Err(err) => return Err(arguments_error(
+                clap::error::ErrorKind::Io,
+                format!("failed to read `{}`: {}", err.path().display(), err.error())
+            ))
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err(arguments_error(
+            clap::error::ErrorKind::InvalidValue,
+            format!("no PNG file matches the pattern `{pattern}`")
+        ));
+    }
+
+    Ok(inputs)
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+// The extensions of the files picked from patterns and directories
+#[cfg(not(feature = "jpeg"))]
+const INPUT_EXTENSIONS: &[&str] = &["png"];
+#[cfg(feature = "jpeg")]
+const INPUT_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg"];
+
+// Files are only told apart by their extension here, the decoder looks at
+// their content
+fn is_input(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| INPUT_EXTENSIONS.iter().any(|input| ext.eq_ignore_ascii_case(input)))
+}
+
+struct DirectoryWalk {
+    files: Vec<(PathBuf, PathBuf)>,
+    errors: usize,
+    verbosity: Verbosity,
+
+    // Canonical paths of the directories already walked, so that symlink
+    // loops don't make the walk go on forever
+    visited: HashSet<PathBuf>
+}
+
+impl DirectoryWalk {
+    fn walk(&mut self, dir: &Path, out_dir: &Path) {
+        match dir.canonicalize() {
+            Ok(canonical) => if !self.visited.insert(canonical) {
+                log!(self.verbosity, Verbose, "Skipping `{}`: directory already visited", dir.display());
+                return;
+            },
+            Err(err) => {
+                message!("ERROR: Failed to read `{}`: {err}", dir.display());
+                self.errors += 1;
+                return;
+            }
+        }
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                message!("ERROR: Failed to read `{}`: {err}", dir.display());
+                self.errors += 1;
+                return;
+            }
+        };
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => paths.push(entry.path()),
+                Err(err) => {
+                    message!("ERROR: Failed to read `{}`: {err}", dir.display());
+                    self.errors += 1;
+                }
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            let out_path = out_dir.join(path.file_name().unwrap_or_default());
+            if path.is_dir() {
+                self.walk(&path, &out_path);
+            } else if is_input(&path) {
+                self.files.push((path, out_path));
+            } else {
+                log!(self.verbosity, Verbose, "Skipping `{}`: not a PNG file", path.display());
+            }
+        }
+    }
+}
+
+// The configuration file looked for in the current directory
+const CONFIG_FILE: &str = "pngpart.toml";
+const ENV_PREFIX: &str = "PNGPART_";
+
+// Checks a value with the parser of its option alone, so that an invalid
+// default is reported along with where it comes from
+fn check_value(arg: &clap::Arg, value: &str, origin: &str) -> Result<(), clap::Error> {
+    let check = clap::Arg::new("value")
+        .value_parser(arg.get_value_parser().clone())
+        .value_delimiter(arg.get_value_delimiter())
+        .allow_hyphen_values(true);
+    let err = match clap::Command::new("pngpart").no_binary_name(true).arg(check).try_get_matches_from([value]) {
+        Ok(_) => return Ok(()),
+        Err(err) => err
+    };
+
+    let reason = match (std::error::Error::source(&err), err.get(ContextKind::ValidValue)) {
+        (Some(source), _) => format!(": {source}"),
+        (None, Some(valid)) => format!(" (possible values: {valid})"),
+        (None, None) => String::new()
+    };
+
+    Err(Args::command().error(err.kind(), format!("invalid value `{value}` for {origin}{reason}")))
+}
+
+// The text of a value given to an option that takes one, arrays and tables
+// aside
+fn scalar_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        _ => None
+    }
+}
+
+// Turns default options into arguments, leaving out the ones given on the
+// command line and the ones that conflict with them. Every option comes with
+// a description of where it is from for the errors
+fn default_arguments(
+    command: &clap::Command,
+    matches: &clap::ArgMatches,
+    options: Vec<(&clap::Arg, toml::Value, String)>
+) -> Result<Vec<OsString>, clap::Error> {
+    let given = |arg: &clap::Arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+    let conflicts = |a: &clap::Arg, b: &clap::Arg| {
+        command.get_arg_conflicts_with(a).iter().any(|arg| arg.get_id() == b.get_id())
+    };
+
+    let mut arguments = Vec::new();
+    for (arg, value, origin) in options {
+        let overridden = given(arg) || command.get_arguments().any(|other| {
+            given(other) && (conflicts(arg, other) || conflicts(other, arg))
+        });
+
+        if overridden {
+            continue;
+        }
+
+        // Options with an optional value, such as --zopfli, can be enabled
+        // without giving one
+        let optional_value = arg.get_num_args().is_some_and(|range| range.min_values() == 0);
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        let mut set = |value: &toml::Value| -> Result<(), clap::Error> {
+            let Some(value) = scalar_value(value) else {
+                return Err(Args::command().error(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("invalid value for {origin}")
+                ));
+            };
+
+            check_value(arg, &value, &origin)?;
+            arguments.push(format!("{flag}={value}").into());
+            Ok(())
+        };
+
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, toml::Value::Boolean(true) | toml::Value::Integer(1)) => arguments.push(flag.into()),
+            (ArgAction::SetTrue, toml::Value::Boolean(false) | toml::Value::Integer(0)) => {},
+            (ArgAction::Count, toml::Value::Integer(count)) if count >= 0 => {
+                arguments.extend((0..count).map(|_| OsString::from(&flag)));
+            },
+            (ArgAction::Set, toml::Value::Boolean(enabled)) if optional_value => {
+                if enabled {
+                    arguments.push(flag.into());
+                }
+            },
+
+            // Options that take a list, such as --tolerance or --roi, are
+            // given once for every value of an array
+            (ArgAction::Append, toml::Value::Array(values)) => values.iter().try_for_each(&mut set)?,
+            (ArgAction::Set | ArgAction::Append, value) => set(&value)?,
+            _ => return Err(Args::command().error(
+                clap::error::ErrorKind::InvalidValue,
+                format!("invalid value for {origin}")
+            ))
+        }
+    }
+
+    Ok(arguments)
+}
+
+// Turns the options of a configuration file, read from `path`, into arguments
+fn config_arguments(path: &Path, text: &str, matches: &clap::ArgMatches) -> Result<Vec<OsString>, clap::Error> {
+    let table: toml::Table = text.parse().map_err(|err| Args::command().error(
+        clap::error::ErrorKind::InvalidValue,
+        format!("invalid configuration `{}`: {err}", path.display())
+    ))?;
+
+    let command = Args::command();
+    let options = table.into_iter().map(|(key, value)| {
+        let arg = command.get_arguments().find(|arg| {
+            arg.get_long() == Some(key.as_str()) && !matches!(key.as_str(), "config" | "no-config")
+        });
+
+        match arg {
+            Some(arg) => Ok((arg, value, format!("the key `{key}` in `{}`", path.display()))),
+            None => Err(Args::command().error(
+                clap::error::ErrorKind::UnknownArgument,
+                format!("unknown key `{key}` in `{}`", path.display())
+            ))
+        }
+    }).collect::<Result<_, _>>()?;
+
+    default_arguments(&command, matches, options)
+}
+
+// Turns the `PNGPART_*` environment variables, looked up with `var`, into
+// arguments. Their values are read as the TOML values they look like, so
+// `PNGPART_NO_OPTIMIZE=true` is the same as `no-optimize = true`
+fn env_arguments(
+    matches: &clap::ArgMatches,
+    var: impl Fn(&str) -> Option<OsString>
+) -> Result<Vec<OsString>, clap::Error> {
+    let command = Args::command();
+    let options = command.get_arguments().filter_map(|arg| {
+        let name = format!("{ENV_PREFIX}{}", arg.get_long()?.to_ascii_uppercase().replace('-', "_"));
+        let value = match var(&name)?.into_string() {
+            Ok(value) => value,
+            Err(_) => return Some(Err(Args::command().error(
+                clap::error::ErrorKind::InvalidUtf8,
+                format!("the environment variable `{name}` is not valid unicode")
+            )))
+        };
+
+        let value = match value.as_str() {
+            "true" => toml::Value::Boolean(true),
+            "false" => toml::Value::Boolean(false),
+            _ => value.parse().map_or(toml::Value::String(value), toml::Value::Integer)
+        };
+
+        Some(Ok((arg, value, format!("the environment variable `{name}`"))))
+    }).collect::<Result<_, _>>()?;
+
+    default_arguments(&command, matches, options)
+}
+
+// Parses the command line, filling the options it doesn't give from the
+// environment, then from the configuration file
+fn parse_arguments() -> Result<Args, clap::Error> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    let start = argv.len().min(1);
+    let mut matches = Args::command().try_get_matches_from(&argv)?;
+
+    // Subcommands don't take any option, so the defaults aren't looked for
+    let args = Args::from_arg_matches(&matches)?;
+    if args.command.is_some() {
+        return Ok(args);
+    }
+
+    // The options of the environment take precedence over the file, so they
+    // are parsed as if they were given on the command line
+    let env = env_arguments(&matches, |name| std::env::var_os(name))?;
+    if !env.is_empty() {
+        argv.splice(start..start, env);
+        matches = Args::command().try_get_matches_from(&argv)?;
+    }
+
+    let args = Args::from_arg_matches(&matches)?;
+    let config = match (&args.config, args.no_config) {
+        (Some(path), _) => path.clone(),
+        (None, false) if Path::new(CONFIG_FILE).is_file() => PathBuf::from(CONFIG_FILE),
+        _ => return Ok(args)
+    };
+
+    let text = std::fs::read_to_string(&config).map_err(|err| arguments_error(
+        clap::error::ErrorKind::Io,
+        format!("failed to read `{}`: {err}", config.display())
+    ))?;
+
+    // The options of the file go first, so they never end up after `--`
+    argv.splice(start..start, config_arguments(&config, &text, &matches)?);
+    Args::try_parse_from(argv)
+}
+
+fn get_arguments(args: Args) -> Result<Cli, clap::Error> {
+    let verbosity = match (args.quiet, args.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Normal,
+        (false, 1) => Verbosity::Verbose,
+        (false, _) => Verbosity::Debug
+    };
+    let mut inputs = args.inputs.clone();
+    // Like with `cp`, an existing directory after the inputs is where the
+    // outputs go
+    let names_outputs = args.output.is_some() || args.output_dir.is_some() || args.in_place;
+    let output_dir = match args.output_dir {
+        Some(dir) => Some(dir),
+        None if !names_outputs && !args.recursive && inputs.len() > 1 && inputs.last().is_some_and(|path| path.is_dir()) => {
+            inputs.pop()
+        },
+        None => None
+    };
+    // Otherwise a second path is the output file, unless it is a pattern
+    let is_output = |path: &PathBuf| !path.to_str().is_some_and(is_pattern);
+    let output = match args.output {
+        Some(output) => Some(output),
+        None if inputs.len() == 2 && output_dir.is_none() && args.name_template.is_none() && !args.in_place => {
+            inputs.pop_if(|path| is_output(path))
+        },
+        None => None
+    };
+
+    let weights = match *args.weights.as_slice() {
+        [r, g, b, a] => [r, g, b, a],
+        _ => return Err(arguments_error(
+            clap::error::ErrorKind::WrongNumberOfValues,
+            format!("--weights takes 4 weights for red, green, blue and alpha, not {}", args.weights.len())
+        ))
+    };
+
+    // The image is compressed down to the lowest tolerance, and written on
+    // the way at the other ones
+    let mut snapshots = args.tolerance.clone();
+    snapshots.sort_unstable_by(|a, b| b.cmp(a));
+    snapshots.dedup();
+    let lowest = snapshots.pop();
+
+    // Only the PSNR decides when to stop if it is given
+    let tolerance = match (lowest, args.target_psnr) {
+        (Some(tolerance), _) => tolerance,
+        (None, Some(_)) => 0,
+        (None, None) if args.target_blocks.is_some() => 0,
+        (None, None) => match args.variance_mode {
+            VarianceMode::Mean => 8,
+            VarianceMode::Total => 128
+        }
+    };
+
+    let mut walk = DirectoryWalk {
+        files: Vec::new(),
+        errors: 0,
+        verbosity,
+        visited: HashSet::new()
+    };
+
+    if args.recursive {
+        let input = match inputs.as_slice() {
+            [input] => input,
+            _ => return Err(arguments_error(
+                clap::error::ErrorKind::TooManyValues,
+                "--recursive compresses a single input directory"
+            ))
+        };
+
+        let out_dir = match output.or(output_dir.clone()) {
+            Some(out_dir) => out_dir,
+            None if args.dry_run => PathBuf::new(),
+            None => return Err(arguments_error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "no output directory"
+            ))
+        };
+
+        if !input.is_dir() {
+            return Err(arguments_error(
+                clap::error::ErrorKind::InvalidValue,
+                format!("`{}` is not a directory, which --recursive requires", input.display())
+            ));
+        }
+
+        walk.walk(input, &out_dir);
+
+        // The mirrored names end with the extension of the inputs, unless
+        // they are written in another format
+        for (input, output) in &mut walk.files {
+            if args.format != OutputFormat::Png || !is_png(input) {
+                output.set_extension(args.format.extension());
+            }
+        }
+    } else {
+        let (inputs, relative): (Vec<PathBuf>, Vec<PathBuf>) = inputs.iter()
+            .map(|input| expand_input(verbosity, input))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .unzip();
+
+        walk.files = match (output, &output_dir) {
+            (Some(output), _) => {
+                if inputs.len() > 1 {
+                    return Err(arguments_error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        format!("{} files would be written to one output, use --output-dir to compress all of them", inputs.len())
+                    ));
+                }
+
+                inputs.into_iter().map(|input| (input, output.clone())).collect()
+            },
+            (None, Some(dir)) => inputs.into_iter().zip(relative).map(|(input, relative)| {
+                if is_stdio(&input) {
+                    return Err(arguments_error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--output-dir can't be used when reading from stdin"
+                    ));
+                }
+
+                let mut output = dir.join(relative);
+                output.set_extension(args.format.extension());
+                Ok((input, output))
+            }).collect::<Result<_, _>>()?,
+            // Nothing is written in a dry run, so the outputs are left empty
+            (None, None) if args.dry_run => inputs.into_iter().map(|input| (input, PathBuf::new())).collect(),
+            (None, None) if args.in_place => inputs.into_iter().map(|input| {
+                if is_stdio(&input) {
+                    return Err(arguments_error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--in-place can't be used when reading from stdin"
+                    ));
+                }
+
+                Ok((input.clone(), input))
+            }).collect::<Result<_, _>>()?,
+            (None, None) if args.name_template.is_some() => inputs.into_iter().map(|input| {
+                if is_stdio(&input) {
+                    return Err(arguments_error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--name-template can't be used when reading from stdin"
+                    ));
+                }
+
+                // The template replaces the file name, placing the output
+                // next to its input
+                Ok((input.clone(), input))
+            }).collect::<Result<_, _>>()?,
+            (None, None) if inputs.len() > 1 => return Err(arguments_error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                format!(
+                    "{} inputs were given without an output directory, end them with an existing directory or use \
+                     --output-dir to compress all of them",
+                    inputs.len()
+                )
+            )),
+            (None, None) => return Err(arguments_error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "no output file"
+            ))
+        };
+    }
+
+    if args.watch && walk.files.iter().any(|(input, _)| is_stdio(input)) {
+        return Err(arguments_error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--watch can't be used when reading from stdin"
+        ));
+    }
+
+    if !snapshots.is_empty() && !args.dry_run {
+        if args.in_place {
+            return Err(arguments_error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--in-place can't be used with several tolerances"
+            ));
+        }
+
+        if walk.files.iter().any(|(_, output)| is_stdio(output)) {
+            return Err(arguments_error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "several tolerances can't all be written to stdout"
+            ));
+        }
+    }
+
+    let stats_to_stdout = args.stats.as_deref().is_some_and(is_stdio);
+    if stats_to_stdout && !args.dry_run && walk.files.iter().any(|(_, output)| is_stdio(output)) {
+        return Err(arguments_error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--stats and the output can't both be written to stdout, use --stats=FILE"
+        ));
+    }
+
+    if args.max_colors.is_some() && args.palette == PaletteChoice::Never {
+        return Err(arguments_error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--max-colors writes a palette, which can't be used with --palette never"
+        ));
+    }
+
+    if args.diff.is_some() && walk.files.len() > 1 {
+        return Err(arguments_error(
+            clap::error::ErrorKind::ArgumentConflict,
+            format!("{} files would be compressed, --diff only works with a single one", walk.files.len())
+        ));
+    }
+
+    if args.animate.is_some() && walk.files.len() > 1 {
+        return Err(arguments_error(
+            clap::error::ErrorKind::ArgumentConflict,
+            format!("{} files would be compressed, --animate only works with a single one", walk.files.len())
+        ));
+    }
+
+    // Inputs with the same name from different directories would silently
+    // overwrite each other's output, and a misplaced argument would overwrite
+    // the input. The number of blocks is only known after compressing, so
+    // names that depend on it can't be checked in advance
+    let mut outputs = HashMap::new();
+    for (input, output) in walk.files.iter().filter(|_| !args.dry_run) {
+        let output = match &args.name_template {
+            Some(template) if template.depends_on_result(args.target_size.is_some()) => continue,
+            Some(template) => output.with_file_name(template.expand(input, tolerance, 0)),
+            None => output.clone()
+        };
+
+        if !args.in_place && is_same_file(input, &output) {
+            return Err(arguments_error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("`{}` would be overwritten by its own output, pass --in-place to allow it", input.display())
+            ));
+        }
+
+        if let Some(other) = outputs.insert(output.clone(), input) {
+            return Err(arguments_error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "both `{}` and `{}` would be written to `{}`",
+                    other.display(),
+                    input.display(),
+                    output.display()
+                )
+            ));
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    if args.threads > 1 {
+        return Err(arguments_error(
+            clap::error::ErrorKind::InvalidValue,
+            format!("--threads {} needs the `parallel` feature, this build is sequential", args.threads)
+        ));
+    }
+
+    // There is no point in more threads than files, and --threads caps every
+    // thread, so that `--threads 1` is sequential. Without the `parallel`
+    // feature the files are compressed one at a time
+    let jobs = match args.jobs {
+        0 => std::thread::available_parallelism().map_or(1, usize::from),
+        jobs => jobs
+    }.min(walk.files.len()).max(1);
+    let jobs = match args.threads {
+        _ if !cfg!(feature = "parallel") => 1,
+        0 => jobs,
+        threads => jobs.min(threads)
+    };
+
+    let cli = Cli {
+        create_dirs: (args.recursive || output_dir.is_some()) && !args.dry_run,
+        background: args.background,
+        crop: args.crop,
+        roi: args.roi,
+        resize: args.resize,
+        upscale: args.upscale,
+        name_template: args.name_template,
+        in_place: args.in_place,
+        no_clobber: args.no_clobber,
+        files: walk.files,
+        errors: walk.errors,
+        tolerance,
+        snapshots,
+        target_size: args.target_size,
+        target_size_passes: args.target_size_passes,
+        target_psnr: args.target_psnr,
+        variance_mode: args.variance_mode,
+        metric: args.metric,
+        aggregate: args.aggregate,
+        fill: args.fill,
+        linear: args.linear,
+        alpha_weighted: args.alpha_weighted,
+        weights,
+        max_blocks: args.max_blocks.or(args.target_blocks),
+        target_blocks: args.target_blocks,
+        min_block_size: args.min_block_size,
+        max_depth: args.max_depth,
+        split_mode: args.split_mode,
+        split_strategy: args.split_strategy,
+        format: args.format,
+        color_type: match args.color_type {
+            ColorChoice::Auto => None,
+            ColorChoice::Gray => Some(ColorType::Gray),
+            ColorChoice::GrayAlpha => Some(ColorType::GrayAlpha),
+            ColorChoice::Rgb => Some(ColorType::Rgb),
+            ColorChoice::Rgba => Some(ColorType::Rgba)
+        },
+        force: args.force,
+        palette: args.palette,
+        max_colors: args.max_colors.map(usize::from),
+        optimize: (!args.no_optimize).then_some(OptimizeOptions {
+            level: args.opt_level,
+            zopfli: args.zopfli,
+            strip: args.strip.into(),
+            interlace: args.interlace,
+            keep_color_type: args.color_type != ColorChoice::Auto
+        }),
+        png_compression: args.png_compression.unwrap_or(if args.no_optimize {
+            PngCompression::Best
+        } else {
+            PngCompression::Fast
+        }),
+        #[cfg(feature = "parallel")]
+        threads: args.threads,
+        jobs,
+        dry_run: args.dry_run,
+        watch: args.watch.then(|| Duration::from_millis(args.poll_interval as u64)),
+        dither: args.dither,
+        grid: args.show_grid.then_some(args.grid_color),
+        seed: args.seed.unwrap_or_else(random_seed),
+        stats: args.stats,
+        diff: args.diff,
+        animate: args.animate.map(|path| Animate {
+            path,
+            stride: args.animate_stride,
+            delay: Duration::from_millis(args.animate_delay)
+        }),
+        verbosity
+    };
+
+    // The options are the same for every file, so they are only checked once
+    if let Err(err) = compressor_options(&cli, Vec::new()).build() {
+        return Err(arguments_error(clap::error::ErrorKind::ValueValidation, err));
+    }
+
+    Ok(cli)
+}
+
+// Every `RandomState` is seeded by the system, which is enough entropy for a
+// seed without another dependency
+fn random_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new().build_hasher().finish()
+}
+
+// `-` stands for stdin when used as the input and stdout as the output
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+// Whether both paths exist and point to the same file
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    if is_stdio(a) || is_stdio(b) {
+        return false;
+    }
+
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false
+    }
+}
+
+// Checks for --no-clobber whether the output should be written
+fn should_skip(cli: &Cli, output: &Path) -> bool {
+    if cli.no_clobber && !is_stdio(output) && output.exists() {
+        log!(cli.verbosity, Normal, "Skipping `{}`: the file already exists", output.display());
+        return true;
+    }
+
+    false
+}
+
+// An input as it was read
+struct Loaded {
+    img: Image,
+
+    // The size of the encoded image
+    size: usize,
+
+    // The 8-bit RGBA colors of the palette of an indexed input
+    palette: Option<Vec<[u8; 4]>>,
+
+    // The frames of an animated input, whose image is then the one shown by
+    // viewers that don't support animations
+    animation: Option<Animation>
+}
+
+fn load_image(path: &Path) -> Result<Loaded, PngpartError> {
+    let bytes = if is_stdio(path) {
+        let mut bytes = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        std::fs::read(path)
+    }.map_err(|source| PngpartError::Io { path: path.to_path_buf(), source })?;
+
+    let img = decode_image(bytes.as_slice())?;
+
+    // JPEGs have neither palettes nor frames
+    if is_jpeg(&bytes) {
+        return Ok(Loaded { img, size: bytes.len(), palette: None, animation: None });
+    }
+
+    Ok(Loaded {
+        img,
+        size: bytes.len(),
+        palette: read_palette(bytes.as_slice())?,
+        animation: decode_animation(bytes.as_slice())?
+    })
+}
+
+fn encode_error(err: PngpartError, path: &Path) {
+    match err {
+        PngpartError::Encode(EncodeError::Header(err)) => message!("ERROR: Failed to generate PNG header: {err}"),
+        PngpartError::Encode(EncodeError::Encode(err)) => message!("ERROR: Failed to encode image to PNG: {err}"),
+        PngpartError::Optimize(err) => {
+            message!("ERROR: Failed to optimize image `{}`: {err}", path.display())
+        },
+        err => message!("ERROR: Failed to encode `{}`: {err}", path.display())
+    }
+}
+
+// The name of an option value as it is given on the command line
+fn value_name(value: impl ValueEnum) -> String {
+    value.to_possible_value().unwrap().get_name().to_string()
+}
+
+// Dithers the compressed image with the random numbers of `rng` and draws the
+// edges of the blocks of `grid` if asked to
+fn decorate_output(cli: &Cli, img: Image, grid: &[Bound], mut rng: SplitMix64) -> Image {
+    let img = match cli.dither {
+        Some(radius) => img.dither(radius, &mut rng),
+        None => img
+    };
+
+    // The grid is drawn over the dithered pixels so its lines stay straight
+    match cli.grid {
+        Some(color) => img.outline(grid, color),
+        None => img
+    }
+}
+
+// Decorates the compressed image, and converts it to the color type it is
+// written with
+fn convert_output(cli: &Cli, img: Image, grid: &[Bound], rng: SplitMix64, input: &Path) -> Option<Image> {
+    // Dithering only scatters the colors of the blocks, so it never stands in
+    // the way of a lossless conversion
+    let img = decorate_output(cli, img, grid, rng);
+
+    let color_type = match cli.color_type {
+        Some(color_type) => {
+            if !cli.force && !img.converts_losslessly(color_type) {
+                message!(
+                    "ERROR: Converting `{}` to {} would lose information, pass --force to allow it",
+                    input.display(),
+                    value_name(ColorChoice::from(color_type))
+                );
+                return None;
+            }
+
+            color_type
+        },
+        None => img.smallest_color_type()
+    };
+
+    if color_type == img.color_type() {
+        return Some(img);
+    }
+
+    log!(
+        cli.verbosity, Verbose,
+        "Converting from {} to {}",
+        value_name(ColorChoice::from(img.color_type())),
+        value_name(ColorChoice::from(color_type))
+    );
+    Some(img.to_color_type(color_type))
+}
+
+// The blocks whose edges are drawn over the output, none unless --show-grid is
+// given
+fn grid_bounds(cli: &Cli, compressor: &Compressor) -> Vec<Bound> {
+    match cli.grid {
+        Some(_) => compressor.blocks().iter().map(|block| block.bound).collect(),
+        None => Vec::new()
+    }
+}
+
+// Adds the blocks so far as the next frame of --animate, outlined if the grid is
+// shown. Once a frame fails, the error is kept and no more frames are added
+fn add_frame(cli: &Cli, animation: &mut Result<GifEncoder, EncodeError>, compressor: &Compressor) {
+    if let Ok(encoder) = animation {
+        let img = match cli.grid {
+            Some(color) => compressor.render().outline(&grid_bounds(cli, compressor), color),
+            None => compressor.render()
+        };
+
+        if let Err(err) = encoder.add_frame(&img) {
+            *animation = Err(err);
+        }
+    }
+}
+
+// How long every stage of the compression of a file took, the stages that
+// were skipped are left empty
+#[derive(Default)]
+struct Timings {
+    decode: Duration,
+    resize: Option<Duration>,
+    search: Option<Duration>,
+    compress: Duration,
+    reconstruct: Option<Duration>,
+    encode: Option<Duration>,
+    optimize: Option<Duration>,
+    write: Option<Duration>
+}
+
+impl Timings {
+    fn stages(&self) -> [(&'static str, Option<Duration>); 8] {
+        [
+            ("decode", Some(self.decode)),
+            ("resize", self.resize),
+            ("search", self.search),
+            ("compress", Some(self.compress)),
+            ("reconstruct", self.reconstruct),
+            ("encode", self.encode),
+            ("optimize", self.optimize),
+            ("write", self.write)
+        ]
+    }
+
+    // One `Time <stage>: <seconds>s` line per stage, in the order they run
+    fn print(&self) {
+        let mut total = Duration::ZERO;
+        for (stage, time) in self.stages() {
+            if let Some(time) = time {
+                message!("Time {stage}: {:.6}s", time.as_secs_f64());
+                total += time;
+            }
+        }
+
+        message!("Time total: {:.6}s", total.as_secs_f64());
+    }
+}
+
+// Encodes the image to PNG and optimizes it, `path` is the input it comes from
+fn encode_image(
+    cli: &Cli,
+    img: &Image,
+    path: &Path,
+    palette: Option<&[[u8; 4]]>,
+    timings: &mut Timings
+) -> Option<Vec<u8>> {
+    let start = Instant::now();
+    let encoded = match encode_unoptimized(cli, img, palette) {
+        Ok(encoded) => encoded,
+        Err(err) => {
+            encode_error(err, path);
+            return None;
+        }
+    };
+
+    optimize_output(cli, encoded, start.elapsed(), path, timings)
+}
+
+// Optimizes an encoded PNG with --opt-level unless --no-optimize is given,
+// `elapsed` is how long the encoding took
+fn optimize_output(
+    cli: &Cli,
+    encoded: Vec<u8>,
+    elapsed: Duration,
+    path: &Path,
+    timings: &mut Timings
+) -> Option<Vec<u8>> {
+    timings.encode = Some(elapsed);
+    log!(cli.verbosity, Verbose, "Encoded {} bytes in {elapsed:.2?}", encoded.len());
+
+    let options = match &cli.optimize {
+        Some(options) => options,
+        None => return Some(encoded)
+    };
+
+    let spinner = progress_bar(cli, None, "{spinner} Optimizing ({elapsed})");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+
+    let start = Instant::now();
+    let optimized = optimize_png(&encoded, options);
+    let elapsed = start.elapsed();
+    timings.optimize = Some(elapsed);
+    spinner.finish_and_clear();
+
+    let optimized = match optimized {
+        Ok(optimized) => optimized,
+        Err(err) => {
+            encode_error(err, path);
+            return None;
+        }
+    };
+
+    log!(
+        cli.verbosity, Verbose,
+        "Optimized to {} bytes in {elapsed:.2?}, saving {} bytes",
+        optimized.len(), encoded.len().saturating_sub(optimized.len())
+    );
+
+    // Comparing with the standard deflater takes another optimization pass
+    if options.zopfli.is_some() && cli.verbosity >= Verbosity::Verbose {
+        let standard = OptimizeOptions { zopfli: None, ..options.clone() };
+        match optimize_png(&encoded, &standard) {
+            Ok(standard) => message!(
+                "Zopfli saved {} bytes over the standard deflater",
+                standard.len() as i64 - optimized.len() as i64
+            ),
+            Err(err) => {
+                encode_error(err, path);
+                return None;
+            }
+        }
+    }
+
+    Some(optimized)
+}
+
+// Encodes an image without optimizing it, with a palette if --palette or
+// --max-colors asks for one. `palette` is the palette of the input, if it has
+// one
+fn encode_unoptimized(cli: &Cli, img: &Image, palette: Option<&[[u8; 4]]>) -> Result<Vec<u8>, PngpartError> {
+    let indexed = match (cli.palette, cli.max_colors, palette) {
+        (PaletteChoice::Never, ..) => None,
+        (_, Some(colors), _) => Some(img.quantize(colors)),
+        (_, None, Some(palette)) if cli.color_type.is_none() => {
+            Some(img.to_indexed().unwrap_or_else(|| img.to_palette(palette)))
+        },
+        (PaletteChoice::Force, None, _) => Some(img.quantize(256)),
+        _ => None
+    };
+
+    match indexed {
+        Some(indexed) => {
+            log!(cli.verbosity, Verbose, "Writing a palette of {} colors", indexed.palette.len());
+            encode_indexed_png(&indexed, cli.png_compression.into())
+        },
+        None => encode_png_with(img, cli.png_compression.into())
+    }
+}
+
+// Renders the compressed image in the output format, ready to be written
+fn encode_output(
+    cli: &Cli,
+    compressor: Compressor,
+    input: &Path,
+    palette: Option<&[[u8; 4]]>,
+    timings: &mut Timings
+) -> Option<Vec<u8>> {
+    if cli.format != OutputFormat::Png {
+        let start = Instant::now();
+        let text = match cli.format {
+            #[cfg(feature = "serde")]
+            OutputFormat::Json => compressor.to_json(),
+            _ => compressor.to_svg()
+        };
+        let elapsed = start.elapsed();
+        timings.encode = Some(elapsed);
+        log!(
+            cli.verbosity, Verbose,
+            "Rendered {} bytes of {} in {elapsed:.2?}", text.len(), cli.format.extension().to_uppercase()
+        );
+
+        return Some(text.into_bytes());
+    }
+
+    let grid = grid_bounds(cli, &compressor);
+    let rng = compressor.rng();
+    let start = Instant::now();
+    let img = compressor.reconstruct();
+    let elapsed = start.elapsed();
+    timings.reconstruct = Some(elapsed);
+    log!(cli.verbosity, Verbose, "Reconstructed in {elapsed:.2?}");
+
+    let img = convert_output(cli, img, &grid, rng, input)?;
+    encode_image(cli, &img, input, palette, timings)
+}
+
+// Writes `bytes` next to `path` before moving them over it, so that the file
+// is either left untouched or completely replaced
+fn replace_file(bytes: &[u8], path: &Path) -> std::io::Result<()> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".pngpart-tmp");
+    let temp = path.with_file_name(name);
+
+    let result = std::fs::write(&temp, bytes).and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+
+    result
+}
+
+// Set once the reading end of a pipe that is written to went away. There is
+// nobody left to report failures to, so no more files are compressed and
+// `main` exits without a summary
+static PIPE_CLOSED: AtomicBool = AtomicBool::new(false);
+
+// Whether `err` means that the reading end of the pipe went away, which is
+// remembered in `PIPE_CLOSED`
+fn pipe_closed(err: &std::io::Error) -> bool {
+    let closed = err.kind() == std::io::ErrorKind::BrokenPipe;
+    if closed {
+        PIPE_CLOSED.store(true, Ordering::Relaxed);
+    }
+
+    closed
+}
+
+// Files that are being read, such as the input or a watched output, are
+// replaced rather than overwritten
+fn write_output(bytes: &[u8], path: &Path, replace: bool) -> bool {
+    let result = if is_stdio(path) {
+        // Rust never translates line endings on stdout, so the bytes are not
+        // mangled on Windows either
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes).and_then(|_| stdout.flush())
+    } else if replace {
+        replace_file(bytes, path)
+    } else {
+        std::fs::write(path, bytes)
+    };
+
+    if let Err(err) = result {
+        if pipe_closed(&err) {
+            return false;
+        }
+
+        message!("ERROR: Failed to write image to `{}`: {err}", path.display());
+        return false;
+    }
+
+    true
+}
+
+// How many blocks are added between two updates of the progress bar
+const PROGRESS_STEP: usize = 1000;
+
+// How many blocks are added between two progress reports with `-vv`, they
+// are also printed at least every `PROGRESS_PERIOD`
+const PROGRESS_INTERVAL: usize = 10000;
+const PROGRESS_PERIOD: Duration = Duration::from_secs(1);
+
+const PROGRESS_LENGTH: u64 = 1000;
+
+fn progress_bar(cli: &Cli, length: Option<u64>, template: &str) -> ProgressBar {
+    // The bars of files compressed at once would draw over each other
+    if cli.verbosity == Verbosity::Quiet || cli.jobs > 1 {
+        return ProgressBar::hidden();
+    }
+
+    // The bar is hidden when stderr is not a terminal
+    let bar = ProgressBar::with_draw_target(length, ProgressDrawTarget::stderr());
+    bar.set_style(ProgressStyle::with_template(template).unwrap().progress_chars("=> "));
+    bar
+}
+
+// The state of the compression that the progress is measured from
+struct Progress {
+    tolerance: u64,
+    variance: u64,
+    psnr: f64
+}
+
+// The total iteration count isn't known in advance, but the maximum variance
+// decreases roughly exponentially towards the tolerance, so it is tracked on
+// a log scale. The PSNR is already logarithmic. Reaching the maximum number of
+// blocks also finishes the job
+fn compress_progress(cli: &Cli, start: &Progress, compressor: &Compressor) -> u64 {
+    let ln = |variance: u64| (variance.max(1) as f64).ln();
+
+    let variance = compressor.max_variance().unwrap_or(0);
+    let range = ln(start.variance) - ln(start.tolerance);
+    let mut progress = if range > 0.0 {
+        (ln(start.variance) - ln(variance)) / range
+    } else {
+        1.0
+    };
+
+    if let Some(target) = cli.target_psnr {
+        let range = target - start.psnr;
+        progress = progress.max(if range > 0.0 {
+            (compressor.psnr() - start.psnr) / range
+        } else {
+            1.0
+        });
+    }
+
+    let blocks = compressor.block_count();
+    if let Some(max_blocks) = cli.max_blocks {
+        progress = progress.max(blocks as f64 / max_blocks as f64);
+    }
+
+    (progress.clamp(0.0, 1.0) * PROGRESS_LENGTH as f64) as u64
+}
+
+// What --stats reports about a compressed file
+struct Stats {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    width: usize,
+    height: usize,
+    input_size: usize,
+
+    // The estimated size in a dry run
+    output_size: usize,
+
+    blocks: usize,
+    max_variance: u64,
+    mean_squared_error: f64,
+    psnr: f64,
+    tolerance: u64,
+    timings: Timings
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
         }
+    }
+    json.push('"');
+    json
+}
+
+// JSON has no infinity, which is the PSNR of identical images
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+fn json_option(value: Option<impl std::fmt::Display>) -> String {
+    value.map_or(String::from("null"), |value| value.to_string())
+}
+
+impl Stats {
+    fn to_json(&self, cli: &Cli) -> String {
+        let seconds = |time: Duration| json_number(time.as_secs_f64());
+        let optimize = match &cli.optimize {
+            Some(options) => format!(
+                "{{\"level\":{},\"zopfli\":{},\"strip\":{},\"interlace\":{}}}",
+                options.level,
+                json_option(options.zopfli),
+                json_string(&value_name(StripMode::from(options.strip))),
+                options.interlace
+            ),
+            None => String::from("null")
+        };
+
+        let settings = [
+            format!("\"tolerance\":{}", self.tolerance),
+            format!("\"target_size\":{}", json_option(cli.target_size)),
+            format!("\"target_psnr\":{}", json_option(cli.target_psnr.map(json_number))),
+            format!("\"variance_mode\":{}", json_string(&value_name(cli.variance_mode))),
+            format!("\"metric\":{}", json_string(&value_name(cli.metric))),
+            format!("\"aggregate\":{}", json_string(&value_name(cli.aggregate))),
+            format!("\"fill\":{}", json_string(&value_name(cli.fill))),
+            format!("\"linear\":{}", cli.linear),
+            format!("\"alpha_weighted\":{}", cli.alpha_weighted),
+            format!("\"weights\":[{}]", cli.weights.map(json_number).join(",")),
+            format!("\"max_blocks\":{}", json_option(cli.max_blocks)),
+            format!("\"target_blocks\":{}", json_option(cli.target_blocks)),
+            format!("\"min_block_size\":{}", cli.min_block_size),
+            format!("\"max_depth\":{}", json_option(cli.max_depth)),
+            format!("\"split_mode\":{}", json_string(&value_name(cli.split_mode))),
+            format!("\"split_strategy\":{}", json_string(&value_name(cli.split_strategy))),
+            format!("\"format\":{}", json_string(&value_name(cli.format))),
+            format!(
+                "\"color_type\":{}",
+                json_string(&value_name(cli.color_type.map_or(ColorChoice::Auto, ColorChoice::from)))
+            ),
+            format!("\"palette\":{}", json_string(&value_name(cli.palette))),
+            format!("\"max_colors\":{}", json_option(cli.max_colors)),
+            format!("\"png_compression\":{}", json_string(&value_name(cli.png_compression))),
+            format!("\"dither\":{}", json_option(cli.dither)),
+            format!(
+                "\"grid\":{}",
+                json_option(cli.grid.map(|[r, g, b]| json_string(&format!("#{r:02x}{g:02x}{b:02x}"))))
+            ),
+            format!("\"seed\":{}", cli.seed),
+            format!("\"optimize\":{optimize}")
+        ];
+
+        let timings = self.timings.stages().map(|(stage, time)| {
+            format!("\"{stage}\":{}", json_option(time.map(seconds)))
+        });
+
+        let fields = [
+            format!("\"input\":{}", json_string(&self.input.to_string_lossy())),
+            format!(
+                "\"output\":{}",
+                json_option(self.output.as_ref().map(|output| json_string(&output.to_string_lossy())))
+            ),
+            format!("\"dry_run\":{}", cli.dry_run),
+            format!("\"width\":{}", self.width),
+            format!("\"height\":{}", self.height),
+            format!("\"input_size\":{}", self.input_size),
+            format!("\"output_size\":{}", self.output_size),
+            format!("\"blocks\":{}", self.blocks),
+            format!("\"max_variance\":{}", self.max_variance),
+            format!("\"mean_squared_error\":{}", json_number(self.mean_squared_error)),
+            format!("\"psnr\":{}", json_number(self.psnr)),
+            format!("\"timings\":{{{}}}", timings.join(",")),
+            format!("\"settings\":{{{}}}", settings.join(","))
+        ];
+
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+// Places the regions of interest on the image that is compressed, which was
+// cropped then scaled from `cropped` to `size` pixels. Regions that end up
+// outside of it are left out with a warning
+fn roi_regions(cli: &Cli, input: &Path, cropped: (usize, usize), size: (usize, usize)) -> Vec<Region> {
+    let (x, y) = cli.crop.map_or((0, 0), |crop| (crop.x, crop.y));
+    let scale = |n: usize, (from, to): (usize, usize), round_up: bool| {
+        let n = n * to;
+        if round_up { n.div_ceil(from) } else { n / from }
     };
 
-    match args.next() {
-        Some(out_file) => (in_file.into(), out_file.into()),
-        None => {
-            eprintln!("ERROR: no output file");
-            eprintln!("USAGE: {name} <input file> <output file>");
-            std::process::exit(1);
+    cli.roi.iter().filter_map(|roi| {
+        let rect = &roi.rect;
+        let x_min = rect.x.max(x) - x;
+        let y_min = rect.y.max(y) - y;
+        let x_max = (rect.x.saturating_add(rect.width).saturating_sub(x)).min(cropped.0);
+        let y_max = (rect.y.saturating_add(rect.height).saturating_sub(y)).min(cropped.1);
+        if x_min >= x_max || y_min >= y_max {
+            log!(
+                cli.verbosity, Normal,
+                "WARNING: The region {},{},{}x{} is outside of `{}`",
+                rect.x, rect.y, rect.width, rect.height, input.display()
+            );
+            return None;
         }
+
+        // Round outwards so that a region never vanishes when scaled down
+        let (w, h) = ((cropped.0, size.0), (cropped.1, size.1));
+        let bound = Bound::new(
+            scale(x_min, w, false), scale(x_max, w, true),
+            scale(y_min, h, false), scale(y_max, h, true)
+        );
+
+        Some(Region { bound, tolerance: roi.tolerance })
+    }).collect()
+}
+
+// The PSNR of the output, which the compressor only keeps track of for flat
+// blocks. Gradients are measured on the rendered image
+fn output_psnr(cli: &Cli, compressor: &Compressor) -> f64 {
+    match cli.fill {
+        Fill::Mean => compressor.psnr(),
+        Fill::Bilinear => pngpart::psnr(compressor.image(), &compressor.render())
     }
 }
 
-struct Image {
-    width: usize,
-    height: usize,
+// The settings of the compressor. The parsers of the options validate them one
+// by one, and they are checked together along with the arguments
+fn compressor_options(cli: &Cli, regions: Vec<Region>) -> CompressorOptions {
+    let options = CompressorOptions::new()
+        .tolerance(cli.tolerance)
+        .max_blocks(cli.max_blocks)
+        .min_block(cli.min_block_size)
+        .max_depth(cli.max_depth)
+        .split_strategy(StandardSplit { mode: cli.split_mode.into(), position: cli.split_strategy.into() })
+        .variance_mode(cli.variance_mode.into())
+        .metric(cli.metric.into())
+        .aggregate(cli.aggregate.into())
+        .linear_light(cli.linear)
+        .alpha_weighting(cli.alpha_weighted)
+        .channel_weights(cli.weights)
+        .target_psnr(cli.target_psnr)
+        .regions(regions)
+        .seed(cli.seed);
 
-    data: Box<[u8]>
+    match cli.fill {
+        Fill::Mean => options.fill_strategy(MeanFill),
+        Fill::Bilinear => options.fill_strategy(BilinearFill)
+    }
 }
 
-fn read_image(path: &str) -> Image {
-    let file = match File::open(path) {
-        Ok(file) => file,
-        Err(err) => {
-            eprintln!("ERROR: Failed to open `{path}`: {err}");
-            std::process::exit(1);
+// Finds the lowest tolerance whose output fits in `budget` bytes by doubling
+// the tolerance until the output fits, then bisecting. Returns the tolerance
+// along with its output, which is the smallest one found if none fits
+fn search_tolerance(
+    cli: &Cli,
+    img: &Image,
+    options: &CompressorOptions,
+    input: &Path,
+    palette: Option<&[[u8; 4]]>,
+    budget: usize
+) -> Option<(u64, Vec<u8>)> {
+    let spinner = progress_bar(cli, None, "{spinner} Searching for the tolerance: {msg} ({elapsed})");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+
+    // The outputs of the lowest tolerance that fits and of the highest one
+    // that doesn't, the answer is between them
+    let mut fits: Option<(u64, Vec<u8>)> = None;
+    let mut too_large: Option<(u64, Vec<u8>)> = None;
+
+    let mut tolerance = cli.tolerance;
+    for _ in 0..cli.target_size_passes {
+        spinner.set_message(format!("trying {tolerance}"));
+
+        let mut compressor = Compressor::new(img.clone(), options.clone());
+        compressor.compress(tolerance, cli.max_blocks);
+        let blocks = compressor.block_count();
+
+        let output = spinner.suspend(|| encode_output(cli, compressor, input, palette, &mut Timings::default()))?;
+        spinner.suspend(|| log!(
+            cli.verbosity, Verbose,
+            "Tolerance {tolerance}: {blocks} blocks, {} bytes", output.len()
+        ));
+
+        if output.len() <= budget {
+            fits = Some((tolerance, output));
+        } else if blocks <= 1 {
+            // The tolerance can't make the output any smaller
+            spinner.finish_and_clear();
+            log!(
+                cli.verbosity, Normal,
+                "WARNING: `{}` doesn't fit in {budget} bytes even as a single block", input.display()
+            );
+            return Some((tolerance, output));
+        } else {
+            too_large = Some((tolerance, output));
         }
+
+        tolerance = match (&too_large, &fits) {
+            (Some((low, _)), Some((high, _))) if high - low <= 1 => break,
+            (Some((low, _)), Some((high, _))) => low + (high - low) / 2,
+            (Some((low, _)), None) => low.saturating_mul(2).max(1),
+            (None, Some((0, _))) => break,
+            (None, Some((high, _))) => high / 2,
+            (None, None) => unreachable!()
+        };
+    }
+    spinner.finish_and_clear();
+
+    if fits.is_none() {
+        log!(
+            cli.verbosity, Normal,
+            "WARNING: `{}` doesn't fit in {budget} bytes after {} attempts", input.display(), cli.target_size_passes
+        );
+    }
+
+    fits.or(too_large)
+}
+
+// With several tolerances, the outputs are named after theirs unless the name
+// template already is
+fn tolerance_output(cli: &Cli, output: &Path, tolerance: u64) -> PathBuf {
+    if cli.snapshots.is_empty() || cli.name_template.as_ref().is_some_and(NameTemplate::uses_tolerance) {
+        return output.to_path_buf();
+    }
+
+    let mut name = output.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-t{tolerance}"));
+    if let Some(ext) = output.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+
+    output.with_file_name(name)
+}
+
+// Writes the image as it is at one of the higher tolerances, the compression
+// goes on afterwards
+fn write_snapshot(
+    cli: &Cli,
+    compressor: &Compressor,
+    input: &Path,
+    palette: Option<&[[u8; 4]]>,
+    output: &Path,
+    tolerance: u64
+) -> bool {
+    log!(
+        cli.verbosity, Normal,
+        "Tolerance {tolerance}: {} blocks, PSNR {:.2} dB", compressor.block_count(), compressor.psnr()
+    );
+
+    if cli.dry_run {
+        return true;
+    }
+
+    let output = match &cli.name_template {
+        Some(template) => output.with_file_name(template.expand(input, tolerance, compressor.block_count())),
+        None => output.to_path_buf()
+    };
+    let output = tolerance_output(cli, &output, tolerance);
+
+    if should_skip(cli, &output) {
+        return true;
+    }
+
+    let encoded = match cli.format {
+        OutputFormat::Png => match convert_output(cli, compressor.render(), &grid_bounds(cli, compressor), compressor.rng(), input) {
+            Some(img) => encode_image(cli, &img, input, palette, &mut Timings::default()),
+            None => None
+        },
+        OutputFormat::Svg => Some(compressor.to_svg().into_bytes()),
+        #[cfg(feature = "serde")]
+        OutputFormat::Json => Some(compressor.to_json().into_bytes())
     };
 
-    let mut decoder = png::Decoder::new(file);
-    decoder.set_transformations(png::Transformations::ALPHA);
+    match encoded {
+        Some(encoded) => write_output(&encoded, &output, cli.watch.is_some()),
+        None => false
+    }
+}
+
+// `stats` is only set if the file was compressed
+fn compress_file(cli: &Cli, input: &Path, output: &Path, stats: &mut Option<Stats>) -> bool {
+    // Avoid compressing the image at all if the output is known to exist
+    if !cli.dry_run {
+        let known_output = match &cli.name_template {
+            Some(template) if template.depends_on_result(cli.target_size.is_some()) => None,
+            Some(template) => Some(output.with_file_name(template.expand(input, cli.tolerance, 0))),
+            None => Some(output.to_path_buf())
+        }.map(|output| tolerance_output(cli, &output, cli.tolerance));
+
+        if known_output.is_some_and(|output| should_skip(cli, &output)) {
+            return true;
+        }
+    }
 
-    let mut reader = match decoder.read_info() {
-        Ok(reader) => reader,
+    let start = Instant::now();
+    let Loaded { img, size: input_size, palette, animation } = match load_image(input) {
+        Ok(loaded) => loaded,
+        Err(err @ PngpartError::Io { .. }) => {
+            message!("ERROR: Failed to open {err}");
+            return false;
+        },
         Err(err) => {
-            eprintln!("ERROR: Failed to decode `{path}`: {err}");
-            std::process::exit(1);
+            message!("ERROR: Failed to decode `{}`: {err}", input.display());
+            return false;
         }
     };
 
-    let mut buf = vec![0u8; reader.output_buffer_size()];
-    match reader.next_frame(&mut buf) {
-        Ok(info) => {
-            buf.resize(info.buffer_size(), 0);
-            Image {
-                width: info.width as usize,
-                height: info.height as usize,
-                data: buf.into()
+    let decode_time = start.elapsed();
+    log!(
+        cli.verbosity, Verbose,
+        "Decoded {input_size} bytes ({}x{}) in {decode_time:.2?}", img.width, img.height
+    );
+    let palette = palette.as_deref();
+
+    if let Some(animation) = animation {
+        let timings = Timings { decode: decode_time, ..Timings::default() };
+        return compress_animation(cli, input, output, animation, input_size, timings, stats);
+    }
+
+    let img = match cli.background {
+        Some(background) => img.flatten(background),
+        None => img
+    };
+
+    let img = match cli.crop {
+        Some(crop) if crop.x.saturating_add(crop.width) > img.width || crop.y.saturating_add(crop.height) > img.height => {
+            message!(
+                "ERROR: Can't crop `{}` to {}x{} at {},{}, it is only {}x{}",
+                input.display(), crop.width, crop.height, crop.x, crop.y, img.width, img.height
+            );
+            return false;
+        },
+        Some(crop) => img.crop(crop.x, crop.y, crop.width, crop.height),
+        None => img
+    };
+    let cropped = (img.width, img.height);
+
+    let start = Instant::now();
+    let (img, resize_time) = match cli.resize.map(|resize| resize.apply(img.width, img.height)) {
+        Some((width, height)) if img.width == 0 || img.height == 0 || (width, height) == (img.width, img.height) => {
+            (img, None)
+        },
+        Some((width, height)) if !cli.upscale && width >= img.width && height >= img.height => {
+            log!(
+                cli.verbosity, Verbose,
+                "Not resizing to {width}x{height}, which is larger than the input, pass --upscale to allow it"
+            );
+            (img, None)
+        },
+        Some((width, height)) => {
+            let img = img.resize(width, height);
+            let elapsed = start.elapsed();
+            log!(cli.verbosity, Verbose, "Resized to {width}x{height} in {elapsed:.2?}");
+            (img, Some(elapsed))
+        },
+        None => (img, None)
+    };
+    let (width, height) = (img.width, img.height);
+    let options = compressor_options(cli, roi_regions(cli, input, cropped, (width, height)));
+
+    // The search already encoded the output, compressing the image once more
+    // is much faster than encoding it again
+    let start = Instant::now();
+    let (tolerance, encoded) = match cli.target_size {
+        Some(budget) => match search_tolerance(cli, &img, &options, input, palette, budget) {
+            Some((tolerance, encoded)) => {
+                log!(cli.verbosity, Normal, "Tolerance: {tolerance}");
+                (tolerance, Some(encoded))
+            },
+            None => return false
+        },
+        None => (cli.tolerance, None)
+    };
+    let search_time = cli.target_size.map(|_| start.elapsed());
+
+    let mut compressor = Compressor::new(img, options);
+
+    if cli.create_dirs {
+        if let Some(parent) = output.parent() {
+            if let Err(err) = std::fs::create_dir_all(parent) {
+                message!("ERROR: Failed to create directory `{}`: {err}", parent.display());
+                return false;
+            }
+        }
+    }
+
+    let bar = progress_bar(cli, Some(PROGRESS_LENGTH), "{spinner} Compressing [{bar:40}] {msg} ({elapsed})");
+    let max_blocks = cli.max_blocks.unwrap_or(usize::MAX);
+    let start_progress = Progress {
+        tolerance,
+        variance: compressor.max_variance().unwrap_or(0),
+        psnr: compressor.psnr()
+    };
+    let mut next_report = PROGRESS_INTERVAL;
+    let mut last_report = Instant::now();
+
+    // The animation starts from the single block of the whole image
+    let mut animation = match &cli.animate {
+        Some(animate) => match GifEncoder::new(width, height, animate.delay) {
+            Ok(encoder) => Some(Ok(encoder)),
+            Err(err) => {
+                encode_error(err.into(), &animate.path);
+                return false;
             }
         },
-        Err(err) => {
-            eprintln!("ERROR: Failed to decode `{path}`: {err}");
-            std::process::exit(1);
+        None => None
+    };
+    if let Some(animation) = &mut animation {
+        add_frame(cli, animation, &compressor);
+    }
+    let mut frame_time = Duration::ZERO;
+
+    // Compress in steps so the progress can be reported in between, stopping
+    // at every multiple of the stride of the animation to take a frame
+    let start = Instant::now();
+    let mut compress_to = |compressor: &mut Compressor, tolerance| loop {
+        let step = compressor.block_count().saturating_add(PROGRESS_STEP).min(max_blocks);
+        let frame_at = cli.animate.as_ref().map(|animate| (compressor.split_count() / animate.stride + 1) * animate.stride);
+        let reason = compressor.compress_with(tolerance, Some(step), |event| match frame_at {
+            Some(frame_at) if event.iteration + 1 >= frame_at => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(())
+        });
+
+        if let (StopReason::Interrupted, Some(animation)) = (&reason, &mut animation) {
+            let frame_start = Instant::now();
+            add_frame(cli, animation, compressor);
+            frame_time += frame_start.elapsed();
+            continue;
+        }
+
+        if !matches!(reason, StopReason::MaxBlocks) || step == max_blocks {
+            break reason;
         }
+
+        let blocks = compressor.block_count();
+        let variance = compressor.max_variance().unwrap_or(0);
+        bar.set_position(compress_progress(cli, &start_progress, compressor));
+        bar.set_message(format!("{blocks} blocks, max variance {variance}"));
+
+        if blocks >= next_report || last_report.elapsed() >= PROGRESS_PERIOD {
+            next_report = blocks + PROGRESS_INTERVAL;
+            last_report = Instant::now();
+            bar.suspend(|| log!(
+                cli.verbosity, Debug,
+                "Progress: {blocks} blocks ({} splittable), max variance {variance}, {:.2?}",
+                compressor.splittable_count(), start.elapsed()
+            ));
+        }
+    };
+
+    // Refining the image only splits blocks further, so the higher
+    // tolerances are reached on the way to the lowest one
+    let mut snapshot_time = Duration::ZERO;
+    for &snapshot in &cli.snapshots {
+        compress_to(&mut compressor, snapshot);
+
+        let snapshot_start = Instant::now();
+        if !bar.suspend(|| write_snapshot(cli, &compressor, input, palette, output, snapshot)) {
+            return false;
+        }
+        snapshot_time += snapshot_start.elapsed();
     }
-}
 
-fn save_image(img: Image, path: &str) {
-    let w = img.width as u32;
-    let h = img.height as u32;
-    let buf = &img.data as &[u8];
+    let reason = compress_to(&mut compressor, tolerance);
+    bar.finish_and_clear();
+    let elapsed = start.elapsed() - snapshot_time - frame_time;
 
-    let mut out_buf = Vec::new();
+    // The last frame shows the output, unless it was just taken
+    if let (Some(animate), Some(animation)) = (&cli.animate, &mut animation) {
+        if compressor.split_count() % animate.stride != 0 {
+            add_frame(cli, animation, &compressor);
+        }
+    }
 
-    {
-        let mut encoder = png::Encoder::new(BufWriter::new(&mut out_buf), w, h);
-        encoder.set_color(png::ColorType::Rgba);
-        encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_compression(png::Compression::Fast);
+    if !cli.dry_run {
+        log!(cli.verbosity, Normal, "Iterations: {}", compressor.block_count());
+    }
 
-        let mut writer = match encoder.write_header() {
-            Ok(writer) => writer,
-            Err(err) => {
-                eprintln!("ERROR: Failed to generate PNG header: {err}");
-                std::process::exit(1);
+    // The tolerance is 0, so it is only reached if the image is exact
+    match (cli.target_blocks, &reason) {
+        (Some(target), StopReason::Tolerance) => log!(
+            cli.verbosity, Normal,
+            "`{}` is already exact with {} of the {target} blocks", input.display(), compressor.block_count()
+        ),
+        (Some(target), StopReason::Exhausted) => log!(
+            cli.verbosity, Normal,
+            "WARNING: `{}` can only be split into {} of the {target} blocks", input.display(), compressor.block_count()
+        ),
+        _ => {}
+    }
+
+    if cli.verbosity >= Verbosity::Verbose {
+        message!(
+            "Compressed in {elapsed:.2?} ({:.0} splits/s)",
+            compressor.split_count() as f64 / elapsed.as_secs_f64()
+        );
+
+        match reason {
+            StopReason::Tolerance => message!("Stopped: all blocks are within the tolerance"),
+            StopReason::MaxBlocks => message!("Stopped: reached the maximum number of blocks"),
+            StopReason::TargetPsnr => message!("Stopped: reached the target PSNR"),
+            StopReason::Exhausted => message!("Stopped: no block can be split any further"),
+            StopReason::Interrupted | StopReason::Cancelled => message!("Stopped: interrupted")
+        }
+
+        let stats = compressor.block_stats(tolerance);
+        message!("Blocks below tolerance: {}", stats.below_tolerance);
+        message!("Blocks above tolerance: {}", stats.above_tolerance);
+        message!("Blocks finalized by size: {}", stats.limited_by_size);
+        message!("Blocks finalized by depth: {}", stats.limited_by_depth);
+        message!("PSNR: {:.2} dB", output_psnr(cli, &compressor));
+        message!("SSIM: {:.4}", compressor.ssim());
+    }
+
+    let mut report = Stats {
+        input: input.to_path_buf(),
+        output: None,
+        width,
+        height,
+        input_size,
+        output_size: 0,
+        blocks: compressor.block_count(),
+        max_variance: compressor.max_block_variance(),
+        mean_squared_error: compressor.mean_squared_error(),
+        psnr: output_psnr(cli, &compressor),
+        tolerance,
+        timings: Timings {
+            decode: decode_time,
+            resize: resize_time,
+            search: search_time,
+            compress: elapsed,
+            ..Timings::default()
+        }
+    };
+
+    if cli.dry_run {
+        let size = encoded.map(|encoded| encoded.len());
+        if !print_analysis(cli, compressor, input, palette, size, &mut report) {
+            return false;
+        }
+
+        *stats = Some(report);
+        return true;
+    }
+
+    let output = match &cli.name_template {
+        Some(template) => {
+            let output = output.with_file_name(template.expand(input, tolerance, compressor.block_count()));
+            if !template.depends_on_result(cli.target_size.is_some()) {
+                output
+            } else if !cli.in_place && is_same_file(input, &output) {
+                message!("ERROR: Refusing to overwrite `{}` with its own output", input.display());
+                return false;
+            } else if should_skip(cli, &output) {
+                return true;
+            } else {
+                output
             }
-        };
+        },
+        None => output.to_path_buf()
+    };
+    let output = tolerance_output(cli, &output, tolerance);
 
-        if let Err(err) = writer.write_image_data(buf) {
-            eprintln!("ERROR: Failed to encode image to PNG: {err}");
-            std::process::exit(1);
+    if let (Some(animate), Some(animation)) = (&cli.animate, animation) {
+        match animation.and_then(GifEncoder::finish) {
+            Ok(encoded) => if !write_output(&encoded, &animate.path, cli.watch.is_some()) {
+                return false;
+            },
+            Err(err) => {
+                encode_error(err.into(), &animate.path);
+                return false;
+            }
         }
     }
 
-    let optimized = match oxipng::optimize_from_memory(&out_buf, &oxipng::Options::default()) {
-        Ok(optimized) => optimized,
-        Err(err) => {
-            eprintln!("ERROR: Failed to optimize image `{path}`: {err}");
-            std::process::exit(1);
+    if let Some(path) = &cli.diff {
+        let diff = compressor.error_map();
+        match encode_image(cli, &diff, input, None, &mut Timings::default()) {
+            Some(encoded) => if !write_output(&encoded, path, cli.watch.is_some()) {
+                return false;
+            },
+            None => return false
         }
+    }
+
+    let encoded = match encoded.or_else(|| encode_output(cli, compressor, input, palette, &mut report.timings)) {
+        Some(encoded) => encoded,
+        None => return false
     };
 
-    if let Err(err) = std::fs::write(path, optimized) {
-        eprintln!("ERROR: Failed to write image to `{path}`: {err}");
-        std::process::exit(1);
+    let start = Instant::now();
+    if !write_output(&encoded, &output, cli.in_place || cli.watch.is_some()) {
+        return false;
     }
-}
+    report.timings.write = Some(start.elapsed());
 
-struct Bound {
-    x_min: usize,
-    x_max: usize,
-    y_min: usize,
-    y_max: usize
+    report.output = Some(output);
+    report.output_size = encoded.len();
+    *stats = Some(report);
+    true
 }
 
-impl Bound {
-    fn new(x_min: usize, x_max: usize, y_min: usize, y_max: usize) -> Self {
-        Self { x_min, x_max, y_min, y_max }
+// Compresses every frame of an animated PNG on its own with the same options,
+// keeping where they are drawn and for how long. The options that change the
+// size of the frames or only apply to a single image can't be used with it
+fn compress_animation(
+    cli: &Cli,
+    input: &Path,
+    output: &Path,
+    mut animation: Animation,
+    input_size: usize,
+    mut timings: Timings,
+    stats: &mut Option<Stats>
+) -> bool {
+    let unsupported = [
+        ("--crop", cli.crop.is_some()),
+        ("--resize", cli.resize.is_some()),
+        ("--roi", !cli.roi.is_empty()),
+        ("--target-size", cli.target_size.is_some()),
+        ("several tolerances", !cli.snapshots.is_empty()),
+        ("--format", cli.format != OutputFormat::Png),
+        ("--color-type", cli.color_type.is_some()),
+        ("--palette force", cli.palette == PaletteChoice::Force),
+        ("--max-colors", cli.max_colors.is_some()),
+        ("--dry-run", cli.dry_run),
+        ("--diff", cli.diff.is_some()),
+        ("--animate", cli.animate.is_some())
+    ];
+    if let Some((option, _)) = unsupported.iter().find(|(_, given)| *given) {
+        message!("ERROR: `{}` is animated, which {option} can't be used with", input.display());
+        return false;
     }
-}
 
-fn compute_mean(img: &Image, bound: &Bound) -> [u64; 4] {
-    let mut mean = [0u64; 4];
-    for i in bound.y_min..bound.y_max {
-        for j in bound.x_min..bound.x_max {
-            for k in 0..4 {
-                mean[k] += img.data[4 * (i * img.width + j) + k] as u64;
+    log!(cli.verbosity, Verbose, "Compressing {} frames", animation.frames.len());
+    let options = compressor_options(cli, Vec::new());
+
+    // Frames are compressed with the tolerance of the whole animation, so the
+    // statistics add up over all of them
+    let (mut blocks, mut max_variance, mut squared_error, mut samples) = (0, 0, 0.0, 0);
+    let start = Instant::now();
+    let images = animation.default_image.iter_mut().chain(animation.frames.iter_mut().map(|frame| &mut frame.image));
+    for img in images {
+        let frame = match cli.background {
+            Some(background) => img.flatten(background),
+            None => img.clone()
+        };
+        samples += frame.width * frame.height * frame.channels;
+
+        let mut compressor = Compressor::new(frame, options.clone());
+        compressor.compress(cli.tolerance, cli.max_blocks);
+
+        let state = compressor.state();
+        blocks += state.blocks;
+        max_variance = max_variance.max(compressor.max_block_variance());
+        squared_error += state.squared_error;
+        *img = decorate_output(cli, compressor.render(), &grid_bounds(cli, &compressor), compressor.rng());
+    }
+    timings.compress = start.elapsed();
+
+    log!(cli.verbosity, Normal, "Iterations: {blocks}");
+    log!(cli.verbosity, Verbose, "Compressed in {:.2?}", timings.compress);
+
+    let output = match &cli.name_template {
+        Some(template) => {
+            let output = output.with_file_name(template.expand(input, cli.tolerance, blocks));
+            if !template.depends_on_result(false) {
+                output
+            } else if !cli.in_place && is_same_file(input, &output) {
+                message!("ERROR: Refusing to overwrite `{}` with its own output", input.display());
+                return false;
+            } else if should_skip(cli, &output) {
+                return true;
+            } else {
+                output
+            }
+        },
+        None => output.to_path_buf()
+    };
+
+    if cli.create_dirs {
+        if let Some(parent) = output.parent() {
+            if let Err(err) = std::fs::create_dir_all(parent) {
+                message!("ERROR: Failed to create directory `{}`: {err}", parent.display());
+                return false;
             }
         }
     }
 
-    for elem in mean.iter_mut() {
-        let w = (bound.x_max - bound.x_min) as u64;
-        let h = (bound.y_max - bound.y_min) as u64;
-        *elem /= w * h;
+    let start = Instant::now();
+    let encoded = match encode_animation(&animation, cli.png_compression.into()) {
+        Ok(encoded) => encoded,
+        Err(err) => {
+            encode_error(err, input);
+            return false;
+        }
+    };
+    let encoded = match optimize_output(cli, encoded, start.elapsed(), input, &mut timings) {
+        Some(encoded) => encoded,
+        None => return false
+    };
+
+    let start = Instant::now();
+    if !write_output(&encoded, &output, cli.in_place || cli.watch.is_some()) {
+        return false;
     }
+    timings.write = Some(start.elapsed());
 
-    mean
+    let mean_squared_error = match samples {
+        0 => 0.0,
+        samples => squared_error / samples as f64
+    };
+    *stats = Some(Stats {
+        input: input.to_path_buf(),
+        output: Some(output),
+        width: animation.width,
+        height: animation.height,
+        input_size,
+        output_size: encoded.len(),
+        blocks,
+        max_variance,
+        mean_squared_error,
+        psnr: 10.0 * (255.0 * 255.0 / mean_squared_error).log10(),
+        tolerance: cli.tolerance,
+        timings
+    });
+    true
 }
 
-struct HeapItem {
-    var: u64,
+// Reports the result of a dry run on stdout, the only file it writes to,
+// unless the statistics are written there. `size` is the size of the output if
+// it was already encoded
+fn print_analysis(
+    cli: &Cli,
+    compressor: Compressor,
+    input: &Path,
+    palette: Option<&[[u8; 4]]>,
+    size: Option<usize>,
+    stats: &mut Stats
+) -> bool {
+    let (label, size) = match (size, cli.format) {
+        (Some(size), _) => ("Size", size),
+        (None, OutputFormat::Png) => {
+            let grid = grid_bounds(cli, &compressor);
+            let rng = compressor.rng();
+            let img = match convert_output(cli, compressor.reconstruct(), &grid, rng, input) {
+                Some(img) => img,
+                None => return false
+            };
 
-    bound: Bound
-}
+            match encode_unoptimized(cli, &img, palette) {
+                Ok(encoded) => ("Estimated size", encoded.len()),
+                Err(err) => {
+                    encode_error(err, input);
+                    return false;
+                }
+            }
+        },
+        (None, OutputFormat::Svg) => ("Size", compressor.to_svg().len()),
+        #[cfg(feature = "serde")]
+        (None, OutputFormat::Json) => ("Size", compressor.to_json().len())
+    };
+    stats.output_size = size;
+
+    let report = format!(
+        "Blocks: {}\nMax variance: {}\nMean squared error: {:.4}\n{label}: {size} bytes\n",
+        stats.blocks, stats.max_variance, stats.mean_squared_error
+    );
 
-impl PartialEq for HeapItem {
-    fn eq(&self, other: &Self) -> bool {
-        self.var == other.var
+    if cli.stats.as_deref().is_some_and(is_stdio) {
+        message!("{}", report.trim_end());
+        return true;
     }
-}
 
-impl PartialOrd for HeapItem {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.var.cmp(&other.var))
+    let mut stdout = std::io::stdout().lock();
+    if let Err(err) = stdout.write_all(report.as_bytes()).and_then(|_| stdout.flush()) {
+        if pipe_closed(&err) {
+            return false;
+        }
+
+        message!("ERROR: Failed to print the analysis: {err}");
+        return false;
     }
+
+    true
 }
 
-impl Ord for HeapItem {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.var.cmp(&other.var)
+// Where the statistics are written, shared by the files compressed at once
+type StatsOut = Mutex<Option<Box<dyn Write + Send>>>;
+
+// Compresses a file and writes its statistics
+fn process_file(cli: &Cli, input: &Path, output: &Path, stats_out: &StatsOut) -> bool {
+    if cli.files.len() > 1 {
+        if cli.name_template.is_some() || cli.dry_run {
+            log!(cli.verbosity, Normal, "Compressing `{}`", input.display());
+        } else {
+            log!(cli.verbosity, Normal, "Compressing `{}` into `{}`", input.display(), output.display());
+        }
     }
-}
 
-impl Eq for HeapItem {}
+    let mut stats = None;
+    let mut success = compress_file(cli, input, output, &mut stats);
 
-impl HeapItem {
-    fn new(img: &Image, bound: Bound) -> Self {
-        let mean = compute_mean(img, &bound);
+    if let Some(stats) = stats.as_ref().filter(|_| cli.verbosity >= Verbosity::Verbose) {
+        stats.timings.print();
+    }
 
-        let mut var = 0;
-        for i in bound.y_min..bound.y_max {
-            for j in bound.x_min..bound.x_max {
-                for k in 0..4 {
-                    let diff = img.data[4 * (i * img.width + j) + k] as i64 - mean[k] as i64;
-                    var += (diff * diff) as u64;
-                }
+    if let (Some(out), Some(stats)) = (stats_out.lock().unwrap().as_mut(), stats) {
+        if let Err(err) = writeln!(out, "{}", stats.to_json(cli)).and_then(|_| out.flush()) {
+            if !pipe_closed(&err) {
+                message!("ERROR: Failed to write the statistics: {err}");
             }
-        }
 
-        Self { var, bound }
+            success = false;
+        }
     }
+
+    success
 }
 
-struct Compressor {
-    img: Image,
-    heap: BinaryHeap<HeapItem>,
+// What the inputs are compared by to find out whether they changed. Editors
+// often save by writing a new file and renaming it over the old one, which
+// leaves the input missing for a moment
+fn file_state(path: &Path) -> Option<(std::time::SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
 }
 
-impl Compressor {
-    fn new(img: Image) -> Self {
-        let mut heap = BinaryHeap::new();
-        heap.push(HeapItem::new(&img, Bound::new(0, img.width, 0, img.height)));
-        Self { img, heap }
+// Compresses the inputs again whenever they change, until Ctrl-C is pressed,
+// which sends to `interrupts`, or the output pipe is closed. An input is only
+// compressed once it looks the same for a whole interval, so that it isn't read
+// while it is being written
+fn watch(cli: &Cli, interval: Duration, stats_out: &StatsOut, interrupts: &Receiver<()>) {
+    log!(cli.verbosity, Normal, "Watching for changes, press Ctrl-C to stop");
+
+    // The state of every input when it was last compressed, and when it was
+    // last checked
+    let mut compressed: Vec<_> = cli.files.iter().map(|(input, _)| file_state(input)).collect();
+    let mut checked = compressed.clone();
+
+    loop {
+        if interrupts.recv_timeout(interval).is_ok() {
+            return;
+        }
+
+        for (i, (input, output)) in cli.files.iter().enumerate() {
+            let state = file_state(input);
+            let stable = state == checked[i];
+            checked[i] = state;
+
+            if state.is_none() || !stable || state == compressed[i] {
+                continue;
+            }
+
+            compressed[i] = state;
+            log!(cli.verbosity, Normal, "`{}` changed", input.display());
+            process_file(cli, input, output, stats_out);
+
+            if PIPE_CLOSED.load(Ordering::Relaxed) {
+                return;
+            }
+        }
     }
+}
+
+// Compresses every file, `cli.jobs` at once on the global thread pool, and
+// returns how many failed
+fn process_files(cli: &Cli, stats_out: &StatsOut) -> usize {
+    let failed = AtomicUsize::new(0);
+
+    let compress = |(input, output): &(PathBuf, PathBuf)| {
+        if PIPE_CLOSED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // While waiting for its own parallel work, a thread of the pool can
+        // pick up another file, so the messages it had are put back after
+        let outer = (cli.jobs > 1).then(|| MESSAGES.replace(Some(String::new())));
+
+        if !process_file(cli, input, output, stats_out) {
+            failed.fetch_add(1, Ordering::Relaxed);
+        }
 
-    fn compress(&mut self, tolerance: u64) {
-        while self.heap.peek().unwrap().var > tolerance {
-            self.add_detail();
+        if let Some(outer) = outer {
+            eprint!("{}", MESSAGES.replace(outer).unwrap_or_default());
         }
+    };
+
+    // Each task takes the next file until there are none left, so that the
+    // files are started in order
+    #[cfg(feature = "parallel")]
+    if cli.jobs > 1 {
+        let next = AtomicUsize::new(0);
+        (0..cli.jobs).into_par_iter().for_each(|_| {
+            while let Some(file) = cli.files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                compress(file);
+            }
+        });
+        return failed.into_inner();
     }
 
-    fn add_detail(&mut self) {
-        let item = self.heap.pop().unwrap();
-        let bound = item.bound;
+    cli.files.iter().for_each(compress);
+    failed.into_inner()
+}
 
-        let split_x = (bound.x_max + bound.x_min) / 2;
-        let split_y = (bound.y_max + bound.y_min) / 2;
+fn main() {
+    let args = parse_arguments().unwrap_or_else(|err| err.exit());
 
-        let bx0 = Bound::new(bound.x_min, split_x, bound.y_min, bound.y_max);
-        let bx1 = Bound::new(split_x, bound.x_max, bound.y_min, bound.y_max);
-        let by0 = Bound::new(bound.x_min, bound.x_max, bound.y_min, split_y);
-        let by1 = Bound::new(bound.x_min, bound.x_max, split_y, bound.y_max);
+    // Subcommands don't compress anything
+    if let Some(Command::Completions { shell }) = args.command {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return;
+    }
 
-        if split_x > bound.x_min && bound.x_max > split_x {
-            let ix0 = HeapItem::new(&self.img, bx0);
-            let ix1 = HeapItem::new(&self.img, bx1);
+    let cli = get_arguments(args).unwrap_or_else(|err| err.exit());
 
-            if split_y > bound.y_min && bound.y_max > split_y {
-                let iy0 = HeapItem::new(&self.img, by0);
-                let iy1 = HeapItem::new(&self.img, by1);
+    // Every parallel task, including oxipng's, runs on the global pool
+    #[cfg(feature = "parallel")]
+    if let Err(err) = rayon::ThreadPoolBuilder::new().num_threads(cli.threads).build_global() {
+        message!("ERROR: Failed to create the thread pool: {err}");
+        std::process::exit(1);
+    }
 
-                if ix0.var + ix1.var < iy0.var + iy1.var {
-                    self.heap.push(ix0);
-                    self.heap.push(ix1);
-                } else {
-                    self.heap.push(iy0);
-                    self.heap.push(iy1);
-                }
-            } else {
-                self.heap.push(ix0);
-                self.heap.push(ix1);
+    let stats_out: Option<Box<dyn Write + Send>> = match &cli.stats {
+        Some(path) if is_stdio(path) => Some(Box::new(std::io::stdout())),
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Some(Box::new(std::io::BufWriter::new(file))),
+            Err(err) => {
+                message!("ERROR: Failed to create `{}`: {err}", path.display());
+                std::process::exit(1);
             }
-        } else {
-            self.heap.push(HeapItem::new(&self.img, by0));
-            self.heap.push(HeapItem::new(&self.img, by1));
-        }
+        },
+        None => None
+    };
+
+    log!(cli.verbosity, Verbose, "Seed: {}", cli.seed);
+
+    let stats_out = Mutex::new(stats_out);
+    let failed = cli.errors + process_files(&cli, &stats_out);
+
+    // Whoever was reading the output is gone, and so is whoever would read the
+    // summary
+    if PIPE_CLOSED.load(Ordering::Relaxed) {
+        std::process::exit(1);
     }
 
-    fn reconstruct(mut self) -> Image {
-        for item in self.heap {
-            let mean = compute_mean(&self.img, &item.bound);
+    let total = cli.files.len() + cli.errors;
+    if failed > 0 && (total > 1 || cli.errors > 0) {
+        message!("ERROR: Failed to compress {failed} of {total} files");
+    } else if failed == 0 && total > 1 {
+        log!(cli.verbosity, Normal, "Compressed {total} files");
+    }
 
-            for i in item.bound.y_min..item.bound.y_max {
-                for j in item.bound.x_min..item.bound.x_max {
-                    let idx = 4 * (i * self.img.width + j);
-                    for k in 0..4 {
-                        self.img.data[idx + k] = mean[k] as u8;
-                    }
-                }
+    if let Some(interval) = cli.watch {
+        let (sender, interrupts) = std::sync::mpsc::channel();
+        let mut interrupted = false;
+        let handler = ctrlc::set_handler(move || {
+            // The current file is finished first, unless Ctrl-C is pressed again
+            if interrupted {
+                std::process::exit(130);
             }
+
+            interrupted = true;
+            let _ = sender.send(());
+        });
+
+        if let Err(err) = handler {
+            message!("ERROR: Failed to handle Ctrl-C: {err}");
+            std::process::exit(1);
         }
 
-        self.img
+        // Watching is meant to end with Ctrl-C, not with the pipe closing
+        watch(&cli, interval, &stats_out, &interrupts);
+        std::process::exit(i32::from(PIPE_CLOSED.load(Ordering::Relaxed)));
+    }
+
+    if failed > 0 {
+        std::process::exit(1);
     }
 }
 
-fn main() {
-    let (in_file, out_file) = get_arguments();
-    let img = read_image(&in_file);
-
-    let mut compressor = Compressor::new(img);
-    compressor.compress(128);
-    eprintln!("Iterations: {}", compressor.heap.len());
-    
-    save_image(compressor.reconstruct(), &out_file);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_templates_expand_every_placeholder() {
+        let template = parse_name_template("{stem}-t{tolerance}.{ext}").unwrap();
+        assert!(!template.uses_blocks());
+        assert_eq!(template.expand(Path::new("images/photo.png"), 64, 0), "photo-t64.png");
+
+        let template = parse_name_template("{blocks}_{stem}{ext}").unwrap();
+        assert!(template.uses_blocks());
+        assert_eq!(template.expand(Path::new("archive.tar.gz"), 0, 1234), "1234_archive.targz");
+        assert_eq!(template.expand(Path::new("noext"), 0, 1), "1_noext");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected_when_parsing() {
+        let Err(err) = parse_name_template("{stem}-{size}.png") else { panic!("`{{size}}` was accepted") };
+        assert!(err.contains("`{size}`"), "{err}");
+        assert!(parse_name_template("{stem").is_err());
+        assert!(parse_name_template("").is_err());
+
+        // Before any input is read
+        let args = Args::try_parse_from(["pngpart", "--name-template", "{size}.png", "does/not/exist.png"]);
+        assert!(args.is_err());
+    }
+
+    // The arguments that the configuration file `config` adds to `argv`
+    fn config_options(argv: &[&str], config: &str) -> Result<Vec<OsString>, clap::Error> {
+        let matches = Args::command().try_get_matches_from(argv).unwrap();
+        config_arguments(Path::new("dir/pngpart.toml"), config, &matches)
+    }
+
+    // Parses `argv` with the arguments of the configuration file `config`
+    fn parse_with_config(argv: &[&str], config: &str) -> Args {
+        let mut full: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        full.splice(1..1, config_options(argv, config).unwrap());
+        Args::try_parse_from(full).unwrap()
+    }
+
+    #[test]
+    fn command_line_options_override_the_configuration() {
+        let config = "tolerance = 64\nmax-blocks = 100\nno-optimize = true\n";
+        let mut options = config_options(&["pngpart", "in.png", "out.png"], config).unwrap();
+        options.sort();
+        assert_eq!(options, ["--max-blocks=100", "--no-optimize", "--tolerance=64"]);
+
+        let args = parse_with_config(&["pngpart", "--tolerance", "16", "in.png", "out.png"], config);
+        assert_eq!((args.tolerance, args.max_blocks, args.no_optimize), (vec![16], Some(100), true));
+    }
+
+    #[test]
+    fn configuration_options_conflicting_with_the_command_line_are_left_out() {
+        let argv = ["pngpart", "-v", "--tolerance", "16", "in.png", "out.png"];
+        assert_eq!(config_options(&argv, "target-size = \"20kb\"\nquiet = true\n").unwrap(), Vec::<OsString>::new());
+
+        let args = parse_with_config(&argv, "target-size = \"20kb\"\nquiet = true\nmax-blocks = 100\n");
+        assert_eq!((args.target_size, args.quiet, args.max_blocks), (None, false, Some(100)));
+    }
+
+    #[test]
+    fn configuration_arrays_give_every_value() {
+        let argv = ["pngpart", "in.png", "out.png"];
+        assert_eq!(config_options(&argv, "tolerance = [512, 128]\n").unwrap(), ["--tolerance=512", "--tolerance=128"]);
+        assert_eq!(parse_with_config(&argv, "tolerance = [512, 128]\n").tolerance, [512, 128]);
+        assert_eq!(parse_with_config(&argv, "tolerance = \"512,128\"\n").tolerance, [512, 128]);
+    }
+
+    #[test]
+    fn invalid_configurations_name_the_key_and_the_file() {
+        let argv = ["pngpart", "in.png", "out.png"];
+        let err = config_options(&argv, "tolerance = [8, -16]\n").unwrap_err().to_string();
+        assert!(err.contains("invalid value `-16` for the key `tolerance` in `dir/pngpart.toml`"), "{err}");
+
+        let err = config_options(&argv, "tolerance = [[8, 16]]\n").unwrap_err().to_string();
+        assert!(err.contains("invalid value for the key `tolerance` in `dir/pngpart.toml`"), "{err}");
+
+        let err = config_options(&argv, "tolerence = 8\n").unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::UnknownArgument);
+        assert!(err.to_string().contains("unknown key `tolerence` in `dir/pngpart.toml`"), "{err}");
+
+        let err = config_options(&argv, "tolerance = \n").unwrap_err().to_string();
+        assert!(err.contains("invalid configuration `dir/pngpart.toml`"), "{err}");
+    }
+
+    // The arguments that the environment variables `vars` add to `argv`
+    fn env_options(argv: &[&str], vars: &[(&str, &str)]) -> Result<Vec<OsString>, clap::Error> {
+        let matches = Args::command().try_get_matches_from(argv).unwrap();
+        env_arguments(&matches, |name| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.into()))
+    }
+
+    #[test]
+    fn environment_variables_come_between_the_command_line_and_the_configuration() {
+        let vars = [
+            ("PNGPART_TOLERANCE", "32"),
+            ("PNGPART_MAX_BLOCKS", "500"),
+            ("PNGPART_NO_OPTIMIZE", "true"),
+            ("TOLERANCE", "1")
+        ];
+        let argv = ["pngpart", "--max-blocks", "100", "in.png", "out.png"];
+        let mut env = env_options(&argv, &vars).unwrap();
+        env.sort();
+        assert_eq!(env, ["--no-optimize", "--tolerance=32"]);
+
+        // The environment is parsed as if it was given on the command line, so
+        // the configuration file only fills the options left
+        let env: Vec<String> = env.into_iter().map(|arg| arg.into_string().unwrap()).collect();
+        let mut argv = argv.to_vec();
+        argv.splice(1..1, env.iter().map(String::as_str));
+        let args = parse_with_config(&argv, "tolerance = 64\nmax-blocks = 1000\nmin-block-size = 4\n");
+        assert_eq!((args.tolerance, args.max_blocks, args.min_block_size), (vec![32], Some(100), 4));
+        assert!(args.no_optimize);
+    }
+
+    #[test]
+    fn invalid_environment_variables_are_named() {
+        let argv = ["pngpart", "in.png", "out.png"];
+        let err = env_options(&argv, &[("PNGPART_TOLERANCE", "-4")]).unwrap_err().to_string();
+        assert!(err.contains("invalid value `-4` for the environment variable `PNGPART_TOLERANCE`"), "{err}");
+
+        let err = env_options(&argv, &[("PNGPART_STRIP", "some")]).unwrap_err().to_string();
+        assert!(err.contains("for the environment variable `PNGPART_STRIP` (possible values: "), "{err}");
+
+        let err = env_options(&argv, &[("PNGPART_NO_OPTIMIZE", "maybe")]).unwrap_err().to_string();
+        assert!(err.contains("invalid value for the environment variable `PNGPART_NO_OPTIMIZE`"), "{err}");
+    }
+
+    // Even without the `parallel` feature, where only 0 and 1 are valid
+    #[test]
+    fn threads_are_accepted_by_every_build() {
+        let argv = ["pngpart", "in.png", "out.png"];
+        assert_eq!(Args::try_parse_from(["pngpart", "--threads", "1", "in.png"]).unwrap().threads, 1);
+        assert_eq!(parse_with_config(&argv, "threads = 1\n").threads, 1);
+        assert_eq!(env_options(&argv, &[("PNGPART_THREADS", "1")]).unwrap(), ["--threads=1"]);
+    }
 }
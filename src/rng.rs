@@ -0,0 +1,28 @@
+/// A small and fast pseudorandom number generator, which gives the same
+/// numbers on every platform for a given seed
+///
+/// This is SplitMix64, it is not suitable for cryptography.
+#[derive(Clone, Debug)]
+pub struct SplitMix64 {
+    state: u64
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 (inclusive) to 1 (exclusive)
+    pub fn next_f64(&mut self) -> f64 {
+        // The 53 high bits fill the mantissa exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
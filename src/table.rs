@@ -0,0 +1,138 @@
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::{read_sample, BitDepth, Bound, Image};
+
+// Channel sums and sums of squares over a set of pixels, the sums of the
+// channels an image doesn't have are left at zero
+#[derive(Clone, Copy, Default)]
+pub struct Sums {
+    sum: [u64; 4],
+    sum_sq: [u64; 4]
+}
+
+impl Sums {
+    fn add(&self, other: &Self) -> Self {
+        let mut result = *self;
+        for k in 0..4 {
+            result.sum[k] += other.sum[k];
+            result.sum_sq[k] += other.sum_sq[k];
+        }
+        result
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        let mut result = *self;
+        for k in 0..4 {
+            result.sum[k] -= other.sum[k];
+            result.sum_sq[k] -= other.sum_sq[k];
+        }
+        result
+    }
+
+    fn add_pixel(&mut self, pixel: &[u8], depth: BitDepth) {
+        for (k, sample) in pixel.chunks(depth.bytes()).enumerate() {
+            let value = read_sample(sample);
+            self.sum[k] += value;
+            self.sum_sq[k] += value * value;
+        }
+    }
+
+    // Average of every channel over `count` pixels, rounded down
+    pub fn mean(&self, count: u64) -> [u64; 4] {
+        self.sum.map(|sum| sum / count)
+    }
+
+    // Average of every channel over `count` pixels whose colors are
+    // premultiplied by their alpha, which goes up to `max` and is the last of
+    // the `channels`. The colors are divided by the summed alpha instead of the
+    // count, which weights them by it, and are 0 if every pixel is transparent
+    pub fn unpremultiplied_mean(&self, count: u64, channels: usize, max: u64) -> [u64; 4] {
+        let alpha = self.sum[channels - 1] as u128;
+        let mut mean = self.mean(count);
+        for (m, &sum) in mean.iter_mut().zip(&self.sum).take(channels - 1) {
+            *m = match alpha {
+                0 => 0,
+                _ => ((sum as u128 * max as u128 + alpha / 2) / alpha) as u64
+            };
+        }
+
+        mean
+    }
+
+    // Summed squared deviation from `mean` over `count` pixels, which may be
+    // any color rather than the actual mean. It expands to
+    // `sum_sq - 2 * mean * sum + count * mean^2`, the terms are expanded in a
+    // u128 since they are much larger than the result, which is at most
+    // 65535^2 per sample and saturates if that doesn't fit in a u64
+    pub fn variance(&self, mean: &[u64; 4], count: u64) -> u64 {
+        let var: u128 = self.deviations(mean, count).iter().sum();
+        u64::try_from(var).unwrap_or(u64::MAX)
+    }
+
+    // Like `variance`, with the deviation of every channel multiplied by its
+    // weight. The result is rounded and saturates the same way
+    pub fn weighted_variance(&self, mean: &[u64; 4], count: u64, weights: &[f64; 4]) -> u64 {
+        let var: f64 = self.deviations(mean, count).iter().zip(weights).map(|(&var, &w)| var as f64 * w).sum();
+        var.round() as u64
+    }
+
+    fn deviations(&self, mean: &[u64; 4], count: u64) -> [u128; 4] {
+        std::array::from_fn(|k| {
+            let m = mean[k] as u128;
+            let (sum, sum_sq) = (self.sum[k] as u128, self.sum_sq[k] as u128);
+            sum_sq + count as u128 * m * m - 2 * m * sum
+        })
+    }
+}
+
+// Summed-area tables of every channel and their squares: the entry at (x, y)
+// holds the sums over the pixels above and to the left of it, so the sums over
+// any block take four lookups. This costs 64 bytes per pixel, and the sums of
+// squares fit in a u64 for 8-bit images of up to 2^48 pixels and 16-bit images
+// of up to 2^32 pixels, which is much more than the memory needed to hold the
+// table itself
+pub struct SummedAreaTable {
+    stride: usize,
+    sums: Box<[Sums]>
+}
+
+impl SummedAreaTable {
+    pub fn new(img: &Image) -> Self {
+        let stride = img.width + 1;
+        let mut sums = vec![Sums::default(); stride * (img.height + 1)];
+
+        // The sums along every row are independent, which is where most of
+        // the work is. Accumulating the rows afterwards only takes additions
+        let pixel_len = img.channels * img.depth.bytes();
+        let row_len = pixel_len * img.width.max(1);
+
+        #[cfg(feature = "parallel")]
+        let rows = sums[stride..].par_chunks_mut(stride).zip(img.data.par_chunks(row_len));
+
+        #[cfg(not(feature = "parallel"))]
+        let rows = sums[stride..].chunks_mut(stride).zip(img.data.chunks(row_len));
+
+        rows.for_each(|(row, pixels)| {
+            let mut sum = Sums::default();
+            for (entry, pixel) in row[1..].iter_mut().zip(pixels.chunks(pixel_len)) {
+                sum.add_pixel(pixel, img.depth);
+                *entry = sum;
+            }
+        });
+
+        for i in stride..sums.len() {
+            sums[i] = sums[i].add(&sums[i - stride]);
+        }
+
+        Self { stride, sums: sums.into() }
+    }
+
+    pub fn sums(&self, bound: &Bound) -> Sums {
+        let at = |x, y| &self.sums[y * self.stride + x];
+
+        // Add before subtracting so intermediate values never underflow
+        at(bound.x_max, bound.y_max).add(at(bound.x_min, bound.y_min))
+            .sub(&at(bound.x_min, bound.y_max).add(at(bound.x_max, bound.y_min)))
+    }
+}